@@ -0,0 +1,262 @@
+use actix_web::cookie::time::OffsetDateTime;
+use actix_web::guard::{Guard, GuardContext};
+use actix_web::http::{header::ACCEPT, StatusCode};
+use actix_web::web::{self, Data, Redirect};
+use actix_web::{get, post, HttpRequest, HttpResponse, Responder, Scope};
+use crate::identity::Identity;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use subtle::ConstantTimeEq;
+use crate::csrf::{CsrfToken, CSRF_TOKEN_KEY};
+use crate::session_hash::HashedKey;
+use crate::stateful_session::{SessionSummary, StatefulSessions};
+use crate::HBS;
+
+/// The sessions admin: lists, inspects, messages and revokes the sessions of `StatefulSessions` (which
+/// must be in the app data), as HTML or, when asked for with `Accept: application/json`, as
+/// JSON.
+///
+/// It's only ever reachable through `guard`: requests it rejects get a plain 404, as if there
+/// was no admin at all.
+pub fn scope(path: &str, guard: impl Guard + 'static) -> Scope {
+    return web::scope(path)
+        .guard(guard)
+        .service(list)
+        .service(revoke_all)
+        .service(inspect)
+        .service(flash)
+        .service(revoke);
+}
+
+/// The role the admin login form grants.
+pub const ADMIN_ROLE: &str = "admin";
+
+/// Lets through requests whose `X-Admin-Token` header holds the given token.
+pub struct AdminToken(String);
+
+impl AdminToken {
+    pub fn new(token: String) -> Self {
+        return AdminToken(token);
+    }
+
+    /// Checks `token` against the expected one, in constant time.
+    fn matches(&self, token: &[u8]) -> bool {
+        return token.ct_eq(self.0.as_bytes()).into();
+    }
+}
+
+impl Guard for AdminToken {
+    fn check(&self, ctx: &GuardContext<'_>) -> bool {
+        return ctx.head()
+            .headers()
+            .get("x-admin-token")
+            .is_some_and(|token| self.matches(token.as_bytes()));
+    }
+}
+
+/// The admin login form, at `path`: whoever submits `token` is signed in as `admin`, holding the
+/// `ADMIN_ROLE`, so that the admin pages can be browsed (and their forms sent, with the session's
+/// CSRF token) without the `X-Admin-Token` header.
+pub fn login_scope(path: &str, token: AdminToken) -> Scope {
+    return web::scope(path)
+        .app_data(Data::new(token))
+        .service(login_form)
+        .service(login);
+}
+
+fn render_login(hb: &HBS<'_>, csrf_token: CsrfToken, status: StatusCode, error: Option<&str>) -> HttpResponse {
+    return match hb.render("admin/login", &json!({"csrf_token": csrf_token, "error": error})) {
+        Ok(body) => HttpResponse::build(status).content_type("text/html").body(body),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    };
+}
+
+#[get("")]
+async fn login_form(hb: HBS<'_>, csrf_token: CsrfToken) -> impl Responder {
+    return render_login(&hb, csrf_token, StatusCode::OK, None);
+}
+
+#[derive(Deserialize)]
+struct AdminLogin {
+    token: String,
+}
+
+#[post("")]
+async fn login(
+    req: HttpRequest,
+    hb: HBS<'_>,
+    csrf_token: CsrfToken,
+    expected: Data<AdminToken>,
+    form: web::Form<AdminLogin>,
+) -> impl Responder {
+    if !expected.matches(form.token.as_bytes()) {
+        tracing::warn!(event = "admin.login_failed");
+        return render_login(&hb, csrf_token, StatusCode::FORBIDDEN, Some("Wrong token."));
+    }
+
+    if let Err(err) = Identity::login(&req, "admin", vec![ADMIN_ROLE.to_string()]) {
+        tracing::error!(event = "identity.login_failed", error = %err);
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    tracing::info!(event = "admin.login");
+    return back_to_list(&req);
+}
+
+fn wants_json(req: &HttpRequest) -> bool {
+    return req.headers()
+        .get(ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/json"));
+}
+
+/// Responds with `data` as JSON, or renders it with `template`. Pages also get the `admin` list
+/// URL and the CSRF token for their forms.
+fn respond(
+    req: &HttpRequest,
+    hb: &HBS<'_>,
+    template: &str,
+    csrf_token: CsrfToken,
+    mut data: Value,
+) -> HttpResponse {
+    if wants_json(req) {
+        return HttpResponse::Ok().json(data);
+    }
+
+    data["admin"] = json!(list_url(req));
+    data["csrf_token"] = json!(csrf_token);
+
+    return match hb.render(template, &data) {
+        Ok(body) => HttpResponse::Ok().content_type("text/html").body(body),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    };
+}
+
+fn summary(session: &SessionSummary) -> Value {
+    return json!({
+        "id": session.hashed_key.to_string(),
+        "bytes": session.bytes,
+        "keys": session.keys,
+        "age": (OffsetDateTime::now_utc() - session.created_at).whole_seconds(),
+        "ttl": session.ttl.whole_seconds(),
+        "user_id": session.user_id,
+    });
+}
+
+#[derive(Deserialize)]
+struct Filter {
+    /// Only lists the sessions holding this key.
+    key: Option<String>,
+}
+
+#[get("", name = "admin_sessions")]
+async fn list(
+    req: HttpRequest,
+    hb: HBS<'_>,
+    csrf_token: CsrfToken,
+    store: Data<StatefulSessions>,
+    filter: web::Query<Filter>,
+) -> impl Responder {
+    let listed = store
+        .list()
+        .iter()
+        .filter(|session| match &filter.key {
+            Some(key) => session.keys.contains(key),
+            None => true,
+        })
+        .map(summary)
+        .collect::<Vec<_>>();
+
+    return respond(&req, &hb, "admin/sessions", csrf_token, json!({
+        "key": filter.key,
+        "sessions": listed,
+    }));
+}
+
+#[get("/{id}")]
+async fn inspect(
+    req: HttpRequest,
+    hb: HBS<'_>,
+    csrf_token: CsrfToken,
+    store: Data<StatefulSessions>,
+    id: web::Path<String>,
+) -> impl Responder {
+    let Some(hashed_key) = HashedKey::parse(&id) else {
+        return HttpResponse::NotFound().finish();
+    };
+
+    let Some(mut state) = store.inspect(&hashed_key) else {
+        return HttpResponse::NotFound().finish();
+    };
+
+    // Showing it would let whoever sees it forge requests on the session's behalf.
+    if let Some(token) = state.get_mut(CSRF_TOKEN_KEY) {
+        *token = json!("[redacted]").to_string();
+    }
+
+    return respond(&req, &hb, "admin/session", csrf_token, json!({
+        "id": hashed_key.to_string(),
+        "map": state,
+    }));
+}
+
+#[derive(Deserialize)]
+struct Message {
+    message: String,
+}
+
+/// Enqueues a flash message for the session's next request.
+#[post("/{id}/flash")]
+async fn flash(
+    req: HttpRequest,
+    store: Data<StatefulSessions>,
+    id: web::Path<String>,
+    form: web::Form<Message>,
+) -> impl Responder {
+    let message = form.into_inner().message;
+    let enqueued = !message.trim().is_empty()
+        && HashedKey::parse(&id).is_some_and(|hashed_key| store.enqueue_flash(&hashed_key, message));
+
+    if wants_json(&req) {
+        return HttpResponse::Ok().json(json!({"enqueued": enqueued}));
+    }
+
+    return back_to_list(&req);
+}
+
+#[post("/{id}/revoke")]
+async fn revoke(
+    req: HttpRequest,
+    store: Data<StatefulSessions>,
+    id: web::Path<String>,
+) -> impl Responder {
+    let revoked = HashedKey::parse(&id).is_some_and(|hashed_key| store.revoke(&hashed_key));
+    if wants_json(&req) {
+        return HttpResponse::Ok().json(json!({"revoked": revoked}));
+    }
+
+    return back_to_list(&req);
+}
+
+#[post("/revoke-all")]
+async fn revoke_all(req: HttpRequest, store: Data<StatefulSessions>) -> impl Responder {
+    let revoked = store.revoke_all();
+    if wants_json(&req) {
+        return HttpResponse::Ok().json(json!({"revoked": revoked}));
+    }
+
+    return back_to_list(&req);
+}
+
+fn list_url(req: &HttpRequest) -> String {
+    return req.url_for_static("admin_sessions")
+        .map(|url| url.path().to_string())
+        .unwrap_or_else(|_| "/".into());
+}
+
+fn back_to_list(req: &HttpRequest) -> HttpResponse {
+    return Redirect::to(list_url(req))
+        .using_status_code(StatusCode::SEE_OTHER)
+        .respond_to(req)
+        .map_into_boxed_body();
+}
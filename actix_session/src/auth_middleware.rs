@@ -0,0 +1,300 @@
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use actix_session::SessionExt;
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::guard::{Guard, GuardContext};
+use actix_web::http::header::LOCATION;
+use actix_web::http::Method;
+use actix_web::{Error, HttpResponse};
+use futures_util::future::LocalBoxFuture;
+use crate::identity::Identity;
+use crate::once_session::OnceSessionExt;
+
+/// Lets only signed in users through. Anyone else is redirected to `login_url`, the page with
+/// the login form, with a flash, and the URL they asked for is remembered for
+/// `redirect_intended`.
+pub struct RequireLogin {
+    login_url: Rc<str>,
+}
+
+/// Lets only signed in users holding the given role through. Anyone else is turned away as
+/// with `RequireLogin`.
+///
+/// It's also a route guard, for routes that should look like they don't exist (`404`) to
+/// anyone else.
+pub struct RequireRole {
+    role: Rc<str>,
+    login_url: Rc<str>,
+}
+
+impl RequireLogin {
+    pub fn new(login_url: &str) -> Self {
+        return RequireLogin { login_url: login_url.into() };
+    }
+}
+
+impl RequireRole {
+    pub fn new(role: &str, login_url: &str) -> Self {
+        return RequireRole { role: role.into(), login_url: login_url.into() };
+    }
+}
+
+impl Guard for RequireRole {
+    fn check(&self, ctx: &GuardContext<'_>) -> bool {
+        return Identity::of(&ctx.get_session())
+            .is_some_and(|identity| identity.has_role(&self.role));
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequireLogin
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequireMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequireMiddleware {
+            service,
+            role: None,
+            login_url: Rc::clone(&self.login_url),
+        }))
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequireRole
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequireMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequireMiddleware {
+            service,
+            role: Some(Rc::clone(&self.role)),
+            login_url: Rc::clone(&self.login_url),
+        }))
+    }
+}
+
+pub struct RequireMiddleware<S> {
+    service: S,
+    role: Option<Rc<str>>,
+    login_url: Rc<str>,
+}
+
+impl<S> RequireMiddleware<S> {
+    /// Returns the message to turn the request away with, if it must be.
+    fn refusal(&self, identity: Option<Identity>) -> Option<&'static str> {
+        let Some(identity) = identity else {
+            return Some("Please sign in to continue.");
+        };
+
+        return match &self.role {
+            Some(role) if !identity.has_role(role) => {
+                Some("You are not allowed to see this page, please sign in with another account.")
+            },
+            _ => None,
+        };
+    }
+}
+
+impl<S, B> Service<ServiceRequest> for RequireMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let session = req.get_session();
+
+        let Some(message) = self.refusal(Identity::of(&session)) else {
+            let fut: <S as Service<ServiceRequest>>::Future = self.service.call(req);
+            return Box::pin(async move {
+                return fut.await.map(ServiceResponse::map_into_left_body);
+            });
+        };
+
+        // Only pages can be gone back to: an intended form submission would be replayed as a GET.
+        if req.method() == Method::GET {
+            if let Err(err) = session.insert_intended(&req.uri().to_string()) {
+                tracing::warn!(event = "session.insert_failed", key = "_intended_url", error = %err);
+            }
+        }
+
+        if let Err(err) = session.insert_flash(message) {
+            tracing::warn!(event = "session.insert_failed", key = "_flash", error = %err);
+        }
+
+        let response = HttpResponse::SeeOther()
+            .insert_header((LOCATION, self.login_url.as_ref()))
+            .finish();
+
+        return Box::pin(ready(Ok(req.into_response(response).map_into_right_body())));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_session::storage::CookieSessionStore;
+    use actix_session::{Session, SessionMiddleware};
+    use actix_web::body::MessageBody;
+    use actix_web::cookie::{Cookie, Key};
+    use actix_web::dev::ServiceFactory;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, web, App, HttpRequest};
+    use serde::Deserialize;
+    use serde_json::Value;
+    use crate::once_session::FLASH_KEY;
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Target {
+        to: String,
+    }
+
+    /// `/account` requires a login and `/admin` the `admin` role, both turning requests away to
+    /// `/login`. `/state` shows the session, `/intend` remembers an intended URL and `/back`
+    /// redirects to it.
+    fn app() -> App<impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = Error,
+        InitError = (),
+    >> {
+        return App::new()
+            .wrap(SessionMiddleware::new(CookieSessionStore::default(), Key::generate()))
+            .route("/sign-in", web::get().to(|req: HttpRequest| async move {
+                Identity::login(&req, "ana", Vec::new()).unwrap();
+                return HttpResponse::Ok().finish();
+            }))
+            .route("/state", web::get().to(|session: Session| async move {
+                return HttpResponse::Ok().json(session.entries().clone());
+            }))
+            .route("/intend", web::get().to(|session: Session, target: web::Query<Target>| async move {
+                session.insert_intended(&target.to).unwrap();
+                return HttpResponse::Ok().finish();
+            }))
+            .route("/back", web::get().to(|session: Session| async move {
+                return session.redirect_intended("/home");
+            }))
+            .service(
+                web::scope("/account")
+                    .wrap(RequireLogin::new("/login"))
+                    .route("", web::to(HttpResponse::Ok))
+            )
+            .service(
+                web::scope("/admin")
+                    .wrap(RequireRole::new("admin", "/login"))
+                    .route("", web::to(HttpResponse::Ok))
+            );
+    }
+
+    /// A request to `uri` carrying the session `cookie`, if there's one yet.
+    fn get(uri: &str, cookie: &Option<Cookie<'static>>) -> test::TestRequest {
+        let req = test::TestRequest::get().uri(uri);
+        return match cookie.clone() {
+            Some(cookie) => req.cookie(cookie),
+            None => req,
+        };
+    }
+
+    /// Sends `req`, keeping the session cookie of the response, if any.
+    async fn send<S, R, B>(app: &S, req: R, cookie: &mut Option<Cookie<'static>>) -> ServiceResponse<B>
+    where
+        S: Service<R, Response = ServiceResponse<B>, Error = Error>,
+    {
+        let res = test::call_service(app, req).await;
+        if let Some(set_cookie) = res.response().cookies().next() {
+            *cookie = Some(set_cookie.into_owned());
+        }
+
+        return res;
+    }
+
+    #[actix_web::test]
+    async fn anonymous_requests_are_redirected_with_a_flash_and_only_pages_are_remembered() {
+        let app = test::init_service(app()).await;
+        let mut cookie = None;
+
+        let res = send(&app, get("/account?tab=1", &cookie).to_request(), &mut cookie).await;
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+        assert_eq!(res.headers().get(LOCATION).unwrap(), "/login");
+
+        let res = send(&app, get("/state", &cookie).to_request(), &mut cookie).await;
+        let state: Value = test::read_body_json(res).await;
+        assert_eq!(state[FLASH_KEY], "\"Please sign in to continue.\"");
+        assert_eq!(state["_intended_url"], "\"/account?tab=1\"");
+
+        let mut cookie = None;
+        let res = send(&app, test::TestRequest::post().uri("/account").to_request(), &mut cookie).await;
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+
+        let res = send(&app, get("/state", &cookie).to_request(), &mut cookie).await;
+        let state: Value = test::read_body_json(res).await;
+        assert!(state.get(FLASH_KEY).is_some());
+        assert!(state.get("_intended_url").is_none());
+    }
+
+    #[actix_web::test]
+    async fn users_without_the_role_are_redirected_with_a_flash() {
+        let app = test::init_service(app()).await;
+        let mut cookie = None;
+        send(&app, get("/sign-in", &cookie).to_request(), &mut cookie).await;
+
+        let res = send(&app, get("/account", &cookie).to_request(), &mut cookie).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = send(&app, get("/admin", &cookie).to_request(), &mut cookie).await;
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+        assert_eq!(res.headers().get(LOCATION).unwrap(), "/login");
+
+        let res = send(&app, get("/state", &cookie).to_request(), &mut cookie).await;
+        let state: Value = test::read_body_json(res).await;
+        assert_eq!(
+            state[FLASH_KEY],
+            "\"You are not allowed to see this page, please sign in with another account.\"",
+        );
+    }
+
+    #[actix_web::test]
+    async fn only_local_paths_are_redirected_to() {
+        let app = test::init_service(app()).await;
+        let targets = [
+            ("/account?tab=1", "/account?tab=1"),
+            ("//evil.example", "/home"),
+            ("/\\evil.example", "/home"),
+            ("https://evil.example/", "/home"),
+        ];
+
+        for (intended, location) in targets {
+            let mut cookie = None;
+            let intend = test::TestRequest::get()
+                .uri(&format!("/intend?{}", serde_urlencoded::to_string([("to", intended)]).unwrap()));
+            send(&app, intend.to_request(), &mut cookie).await;
+
+            let res = send(&app, get("/back", &cookie).to_request(), &mut cookie).await;
+            assert_eq!(res.status(), StatusCode::SEE_OTHER);
+            assert_eq!(res.headers().get(LOCATION).unwrap(), location, "intended {intended}");
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use std::future::{ready, Ready};
+use actix_session::{Session, SessionExt, SessionInsertError};
+use actix_web::{FromRequest, HttpRequest};
+use handlebars::{Context, Handlebars, Helper, HelperResult, Output, RenderContext, RenderErrorReason};
+use serde::Serialize;
+
+pub const CSRF_TOKEN_KEY: &str = "_csrf_token";
+/// The form field the token is expected in.
+pub const CSRF_FIELD: &str = "_csrf";
+/// The header the token is expected in, for requests that aren't form posts.
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+/// The CSRF token of the current session, generated the first time it's asked for. Render it
+/// with the `csrf_field` helper, by passing it to the template as `csrf_token`.
+#[derive(Clone, Serialize)]
+#[serde(transparent)]
+pub struct CsrfToken(String);
+
+impl CsrfToken {
+    pub fn of(session: &Session) -> Result<Self, SessionInsertError> {
+        if let Ok(Some(token)) = session.get::<String>(CSRF_TOKEN_KEY) {
+            return Ok(CsrfToken(token));
+        }
+
+        let token = rand::random::<[u8; 32]>()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+
+        session.insert(CSRF_TOKEN_KEY, &token)?;
+        return Ok(CsrfToken(token));
+    }
+}
+
+impl FromRequest for CsrfToken {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
+        return ready(CsrfToken::of(&req.get_session()).map_err(actix_web::Error::from));
+    }
+}
+
+/// `{{csrf_field}}`: a hidden form field holding the `csrf_token` passed to the template.
+pub fn csrf_field(
+    _: &Helper,
+    _: &Handlebars,
+    ctx: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let token = ctx.data()
+        .get("csrf_token")
+        .and_then(|token| token.as_str())
+        .ok_or_else(|| RenderErrorReason::MissingVariable(Some("csrf_token".into())))?;
+
+    out.write(&format!(
+        r#"<input type="hidden" name="{}" value="{}">"#,
+        CSRF_FIELD,
+        handlebars::html_escape(token)
+    ))?;
+
+    return Ok(());
+}
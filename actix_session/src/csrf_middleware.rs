@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+use std::future::{ready, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use actix_session::SessionExt;
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::PayloadError;
+use actix_web::guard::Guard;
+use actix_web::http::header::{CONTENT_TYPE, LOCATION};
+use actix_web::web::Bytes;
+use actix_web::{Error, HttpMessage, HttpResponse};
+use futures_util::future::LocalBoxFuture;
+use futures_util::Stream;
+use serde_json::json;
+use subtle::ConstantTimeEq;
+use crate::csrf::{CSRF_FIELD, CSRF_HEADER, CSRF_TOKEN_KEY};
+use crate::once_session::{OnceSession, OnceSessionExt};
+
+/// Checks the session's CSRF token on every request with an unsafe method (anything but `GET`,
+/// `HEAD`, `OPTIONS` and `TRACE`), taken from the `X-CSRF-Token` header or, for url-encoded
+/// forms, the `_csrf` field. Requests without the right token never reach the handler: an error
+/// is flashed, and they are redirected back.
+///
+/// Requests accepted by one of the `exempt` guards aren't checked. Only exempt requests carrying
+/// credentials browsers never send on their own, e.g. a token header (see `AdminToken`).
+///
+/// Must be wrapped *before* `FlushOnceSessions` (so it runs after it), to know where back is.
+#[derive(Default)]
+pub struct VerifyCsrf {
+    exempt: Vec<Rc<dyn Guard>>,
+}
+
+impl VerifyCsrf {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    pub fn exempt(mut self, guard: impl Guard + 'static) -> Self {
+        self.exempt.push(Rc::new(guard));
+        return self;
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for VerifyCsrf
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = VerifyCsrfMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(VerifyCsrfMiddleware {
+            service: Rc::new(service),
+            exempt: self.exempt.clone(),
+        }))
+    }
+}
+
+pub struct VerifyCsrfMiddleware<S> {
+    service: Rc<S>,
+    exempt: Vec<Rc<dyn Guard>>,
+}
+
+/// Reads the token out of the form body, then puts the body back for the handler.
+async fn form_token(req: &mut ServiceRequest) -> Result<Option<String>, Error> {
+    let is_form = req.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/x-www-form-urlencoded"));
+
+    if !is_form {
+        return Ok(None);
+    }
+
+    let body = req.extract::<Bytes>().await?;
+    let token = serde_urlencoded::from_bytes::<HashMap<String, String>>(&body)
+        .ok()
+        .and_then(|mut form| form.remove(CSRF_FIELD));
+
+    let stream: Pin<Box<dyn Stream<Item = Result<Bytes, PayloadError>>>> =
+        Box::pin(futures_util::stream::once(ready(Ok(body))));
+    req.set_payload(Payload::from(stream));
+
+    return Ok(token);
+}
+
+impl<S, B> Service<ServiceRequest> for VerifyCsrfMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let exempt = self.exempt.iter().any(|guard| guard.check(&req.guard_ctx()));
+
+        Box::pin(async move {
+            if req.method().is_safe() || exempt {
+                return service.call(req).await.map(ServiceResponse::map_into_left_body);
+            }
+
+            let header_token = req.headers()
+                .get(CSRF_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string);
+
+            let token = match header_token {
+                Some(token) => Some(token),
+                None => form_token(&mut req).await?,
+            };
+
+            let session = req.get_session();
+            let expected = session.get::<String>(CSRF_TOKEN_KEY).unwrap_or(None);
+
+            let valid = match (token, expected) {
+                (Some(token), Some(expected)) => bool::from(token.as_bytes().ct_eq(expected.as_bytes())),
+                _ => false,
+            };
+
+            if valid {
+                return service.call(req).await.map(ServiceResponse::map_into_left_body);
+            }
+
+            let errors = json!({"csrf": "Your form has expired, please try again."});
+            tracing::warn!(event = "csrf.rejected", method = %req.method());
+            if let Err(err) = session.insert_errors(errors) {
+                tracing::warn!(event = "session.insert_failed", key = "_errors", error = %err);
+            }
+
+            let back = req.extensions()
+                .get::<OnceSession>()
+                .map(|once_session| once_session.prev_req.clone())
+                .unwrap_or_else(|| "/".into());
+
+            let response = HttpResponse::SeeOther()
+                .insert_header((LOCATION, back))
+                .finish();
+
+            return Ok(req.into_response(response).map_into_right_body());
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_session::storage::CookieSessionStore;
+    use actix_session::SessionMiddleware;
+    use actix_web::body::BoxBody;
+    use actix_web::cookie::{Cookie, Key};
+    use actix_web::dev::ServiceResponse;
+    use actix_web::guard;
+    use actix_web::http::{Method, StatusCode};
+    use actix_web::{test, web, App};
+    use crate::csrf::CsrfToken;
+    use super::*;
+
+    /// Runs `req` on an app whose `GET /token` hands out the session's CSRF token, with the
+    /// session cookie and token of a first request.
+    async fn call(
+        req: test::TestRequest,
+        with_token: impl FnOnce(test::TestRequest, String) -> test::TestRequest,
+    ) -> ServiceResponse<EitherBody<BoxBody>> {
+        let app = test::init_service(
+            App::new()
+                .wrap(VerifyCsrf::new().exempt(guard::Header("x-api-key", "secret")))
+                .wrap(SessionMiddleware::new(CookieSessionStore::default(), Key::generate()))
+                .route("/token", web::get().to(|token: CsrfToken| async move {
+                    return HttpResponse::Ok().json(token);
+                }))
+                .route("/", web::to(|| async { HttpResponse::Ok().body("handled") })),
+        ).await;
+
+        let res = test::call_service(&app, test::TestRequest::get().uri("/token").to_request()).await;
+        let cookie: Cookie = res.response().cookies().next().unwrap().into_owned();
+        let token = serde_json::from_slice::<String>(&test::read_body(res).await).unwrap();
+
+        return test::call_service(&app, with_token(req.uri("/").cookie(cookie), token).to_request()).await;
+    }
+
+    #[actix_web::test]
+    async fn posts_without_the_token_are_redirected_back() {
+        let res = call(test::TestRequest::post(), |req, _| req).await;
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+        assert_eq!(res.headers().get(LOCATION).unwrap(), "/");
+
+        let res = call(test::TestRequest::post(), |req, _| req.insert_header((CSRF_HEADER, "forged"))).await;
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+    }
+
+    #[actix_web::test]
+    async fn posts_with_the_token_are_handled() {
+        let res = call(test::TestRequest::post(), |req, token| req.insert_header((CSRF_HEADER, token))).await;
+        assert_eq!(test::read_body(res).await, "handled");
+
+        let res = call(test::TestRequest::post(), |req, token| req.set_form([(CSRF_FIELD, token)])).await;
+        assert_eq!(test::read_body(res).await, "handled");
+    }
+
+    #[actix_web::test]
+    async fn safe_and_exempt_requests_are_let_through() {
+        for method in [Method::GET, Method::HEAD, Method::OPTIONS] {
+            let res = call(test::TestRequest::default().method(method), |req, _| req).await;
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+
+        let res = call(test::TestRequest::post(), |req, _| req.insert_header(("x-api-key", "secret"))).await;
+        assert_eq!(test::read_body(res).await, "handled");
+    }
+}
@@ -0,0 +1,339 @@
+use std::collections::{HashMap, HashSet};
+use actix_session::storage::{LoadError, SaveError, SessionKey, SessionStore, UpdateError};
+use actix_web::cookie::time::Duration;
+use actix_web::cookie::Key;
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use crate::session_keys::SessionKeys;
+
+type SessionState = HashMap<String, String>;
+
+/// The entry holding the random id every other entry of the state is bound to. Handed to the
+/// inner store in the clear, and kept in the opened state so it survives the round trip.
+pub const SEAL_ID_KEY: &str = "_sealed_id";
+const NONCE_LEN: usize = 12;
+/// Tell the state keys apart from the keys cookies are signed and encrypted with, even when
+/// both are derived from the same secret.
+const STATE_KEY_INFO: &[u8] = b"sessions-experiment session state v1";
+const NONCE_KEY_INFO: &[u8] = b"sessions-experiment session state nonce v1";
+
+/// A `SessionStore` wrapper that seals every entry of a session state with AES-256-GCM before
+/// handing it to the `inner` store, so whatever the backend persists is never readable in the
+/// clear. Entries are sealed one by one, under their own name, so the inner store still sees
+/// which ones a state holds, and can merge stale updates key by key.
+///
+/// Entries are sealed under a key derived from the active one (never the cookie key itself), and
+/// bound to a random id the session is given on its first save, so a sealed entry moved to
+/// another session (or another name) doesn't open. The id doesn't change when the inner store
+/// moves the session to a new key, so requests still carrying the old one keep their session. On
+/// load, retired keys are tried after the active one, so rotating the key doesn't drop existing
+/// sessions; they are re-sealed under the active key on their next save or update.
+///
+/// Sealing is deterministic: an entry that hasn't changed is handed back to the inner store as it
+/// was loaded, which is what merging relies on.
+///
+/// The inner store can't read sealed entries. Those it needs to (e.g. its conflict-checking
+/// version, or the user to index the session by) must be [`EncryptedSessions::reveal`]ed, as must
+/// those it adds to the states it loads.
+pub struct EncryptedSessions<S> {
+    inner: S,
+    keys: SessionKeys,
+    revealed: HashSet<String>,
+}
+
+impl<S: SessionStore> EncryptedSessions<S> {
+    pub fn new(inner: S, keys: SessionKeys) -> Self {
+        return EncryptedSessions { inner, keys, revealed: HashSet::from([SEAL_ID_KEY.to_string()]) };
+    }
+
+    /// Hands the `key` entry to the inner store in the clear, and takes it as is from the
+    /// states it loads.
+    pub fn reveal(mut self, key: impl Into<String>) -> Self {
+        self.revealed.insert(key.into());
+        return self;
+    }
+
+    fn seal(&self, mut state: SessionState) -> Result<SessionState, anyhow::Error> {
+        let id = state
+            .entry(SEAL_ID_KEY.to_string())
+            .or_insert_with(|| {
+                rand::random::<[u8; 16]>()
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect::<String>()
+            })
+            .clone();
+
+        return state
+            .into_iter()
+            .map(|(name, value)| {
+                if self.revealed.contains(&name) {
+                    return Ok((name, value));
+                }
+
+                let sealed = seal_entry(self.keys.active(), &id, &name, &value)?;
+                return Ok((name, sealed));
+            })
+            .collect();
+    }
+
+    fn open(&self, state: SessionState) -> Result<SessionState, anyhow::Error> {
+        let id = state.get(SEAL_ID_KEY).cloned();
+
+        return state
+            .into_iter()
+            .map(|(name, value)| {
+                if self.revealed.contains(&name) {
+                    return Ok((name, value));
+                }
+
+                let id = id
+                    .as_deref()
+                    .ok_or_else(|| anyhow::anyhow!("Session state has not been sealed."))?;
+
+                let opened = std::iter::once(self.keys.active())
+                    .chain(self.keys.retired())
+                    .find_map(|key| open_entry(key, id, &name, &value))
+                    .ok_or_else(|| anyhow::anyhow!("No session key could open the `{name}` entry."))?;
+
+                return Ok((name, opened));
+            })
+            .collect();
+    }
+}
+
+/// The associated data an entry is sealed with: the session it belongs to, and its name.
+fn aad(id: &str, name: &str) -> Vec<u8> {
+    return [id.as_bytes(), b"\0", name.as_bytes()].concat();
+}
+
+/// Seals `value` as `base64(nonce || ciphertext)`. The nonce is a MAC of the entry, so the same
+/// entry is always sealed the same way under the same key, and different ones never share it.
+fn seal_entry(key: &Key, id: &str, name: &str, value: &str) -> Result<String, anyhow::Error> {
+    let aad = aad(id, name);
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&derive(key, NONCE_KEY_INFO))
+        .expect("HMAC accepts keys of any size");
+    mac.update(&aad);
+    mac.update(b"\0");
+    mac.update(value.as_bytes());
+    let nonce = mac.finalize().into_bytes();
+    let nonce = Nonce::from_slice(&nonce[..NONCE_LEN]);
+
+    let ciphertext = cipher(key)
+        .encrypt(nonce, Payload { msg: value.as_bytes(), aad: &aad })
+        .map_err(|_| anyhow::anyhow!("Failed to encrypt the session state."))?;
+
+    let mut sealed = nonce.to_vec();
+    sealed.extend(ciphertext);
+    return Ok(BASE64.encode(sealed));
+}
+
+fn open_entry(key: &Key, id: &str, name: &str, sealed: &str) -> Option<String> {
+    let sealed = BASE64.decode(sealed).ok()?;
+    if sealed.len() < NONCE_LEN {
+        return None;
+    }
+
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let plaintext = cipher(key)
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: &aad(id, name) })
+        .ok()?;
+
+    return String::from_utf8(plaintext).ok();
+}
+
+/// Derives a 32 bytes key for `info` from the master secret of `key`, with HKDF-SHA256.
+fn derive(key: &Key, info: &[u8]) -> [u8; 32] {
+    let mut derived = [0; 32];
+    Hkdf::<Sha256>::new(None, key.master())
+        .expand(info, &mut derived)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+
+    return derived;
+}
+
+/// The AES-256-GCM cipher entries are sealed with under `key`.
+fn cipher(key: &Key) -> Aes256Gcm {
+    return Aes256Gcm::new_from_slice(&derive(key, STATE_KEY_INFO)).expect("the state key is 32 bytes long");
+}
+
+impl<S: SessionStore> SessionStore for EncryptedSessions<S> {
+    async fn load(
+        &self,
+        session_key: &SessionKey,
+    ) -> Result<Option<SessionState>, LoadError> {
+        return match self.inner.load(session_key).await? {
+            None => Ok(None),
+            Some(state) => self.open(state)
+                .map(Some)
+                .map_err(LoadError::Deserialization),
+        };
+    }
+
+    async fn save(
+        &self,
+        session_state: SessionState,
+        ttl: &Duration,
+    ) -> Result<SessionKey, SaveError> {
+        let sealed = self.seal(session_state).map_err(SaveError::Serialization)?;
+        return self.inner.save(sealed, ttl).await;
+    }
+
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: SessionState,
+        ttl: &Duration,
+    ) -> Result<SessionKey, UpdateError> {
+        let sealed = self.seal(session_state).map_err(UpdateError::Serialization)?;
+        return self.inner.update(session_key, sealed, ttl).await;
+    }
+
+    async fn update_ttl(
+        &self,
+        session_key: &SessionKey,
+        ttl: &Duration,
+    ) -> Result<(), anyhow::Error> {
+        return self.inner.update_ttl(session_key, ttl).await;
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> Result<(), anyhow::Error> {
+        return self.inner.delete(session_key).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stateful_session::{StatefulSessions, VERSION_KEY};
+
+    fn state(entries: &[(&str, &str)]) -> SessionState {
+        return entries
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+    }
+
+    fn store(keys: SessionKeys) -> EncryptedSessions<StatefulSessions> {
+        return EncryptedSessions::new(StatefulSessions::new(), keys).reveal(VERSION_KEY);
+    }
+
+    #[test]
+    fn sealed_state_opens_with_its_revealed_entries_untouched() {
+        let store = store(SessionKeys::generate());
+        let sealed = store.seal(state(&[("name", "\"ana\""), (VERSION_KEY, "3")])).unwrap();
+
+        assert!(!sealed["name"].contains("ana"));
+        assert_eq!(sealed[VERSION_KEY], "3");
+
+        let opened = store.open(sealed.clone()).unwrap();
+        assert_eq!(opened["name"], "\"ana\"");
+        assert_eq!(opened[VERSION_KEY], "3");
+        assert_eq!(opened[SEAL_ID_KEY], sealed[SEAL_ID_KEY]);
+    }
+
+    #[test]
+    fn unchanged_entries_are_sealed_the_same_way() {
+        let store = store(SessionKeys::generate());
+        let sealed = store.seal(state(&[("name", "\"ana\""), ("age", "30")])).unwrap();
+
+        let mut opened = store.open(sealed.clone()).unwrap();
+        opened.insert("age".into(), "31".into());
+        let resealed = store.seal(opened).unwrap();
+
+        assert_eq!(resealed["name"], sealed["name"]);
+        assert_ne!(resealed["age"], sealed["age"]);
+    }
+
+    #[test]
+    fn sealed_entries_moved_to_another_session_or_name_do_not_open() {
+        let store = store(SessionKeys::generate());
+        let ana = store.seal(state(&[("name", "\"ana\""), ("role", "\"admin\"")])).unwrap();
+        let bob = store.seal(state(&[("name", "\"bob\""), ("role", "\"guest\"")])).unwrap();
+
+        let mut moved = bob.clone();
+        moved.insert("role".into(), ana["role"].clone());
+        assert!(store.open(moved).is_err());
+
+        let mut renamed = ana.clone();
+        renamed.insert("role".into(), ana["name"].clone());
+        assert!(store.open(renamed).is_err());
+    }
+
+    #[test]
+    fn state_is_not_sealed_under_the_cookie_key() {
+        let keys = SessionKeys::generate();
+        let sealed = store(keys.clone()).seal(state(&[("name", "\"ana\"")])).unwrap();
+        let entry = BASE64.decode(&sealed["name"]).unwrap();
+        let (nonce, ciphertext) = entry.split_at(NONCE_LEN);
+
+        let cookie_cipher = Aes256Gcm::new_from_slice(keys.active().encryption()).unwrap();
+        let payload = Payload { msg: ciphertext, aad: &aad(&sealed[SEAL_ID_KEY], "name") };
+        assert!(cookie_cipher.decrypt(Nonce::from_slice(nonce), payload).is_err());
+    }
+
+    #[test]
+    fn retired_keys_still_open_states() {
+        let old = Key::generate();
+        let sealed = store(SessionKeys::new(old.clone(), Vec::new()))
+            .seal(state(&[("name", "\"ana\"")]))
+            .unwrap();
+
+        let rotated = store(SessionKeys::new(Key::generate(), vec![old]));
+        assert!(rotated.open(sealed.clone()).is_ok());
+
+        let resealed = rotated.seal(rotated.open(sealed.clone()).unwrap()).unwrap();
+        let without_old = store(SessionKeys::new(rotated.keys.active().clone(), Vec::new()));
+        assert!(without_old.open(resealed).is_ok());
+        assert!(without_old.open(sealed).is_err());
+    }
+
+    #[actix_web::test]
+    async fn sessions_moved_to_a_new_key_still_open_under_the_old_one() {
+        // Rotated on every update.
+        let inner = StatefulSessions::new().rotate_keys(Duration::ZERO, Duration::minutes(1));
+        let store = EncryptedSessions::new(inner, SessionKeys::generate()).reveal(VERSION_KEY);
+        let ttl = Duration::minutes(5);
+
+        let key = store.save(state(&[("name", "\"ana\"")]), &ttl).await.unwrap();
+        let old_key = SessionKey::try_from(key.as_ref().to_string()).unwrap();
+        let loaded = store.load(&key).await.unwrap().unwrap();
+        let new_key = store.update(key, loaded.clone(), &ttl).await.unwrap();
+        assert_ne!(new_key.as_ref(), old_key.as_ref());
+
+        let under_new = store.load(&new_key).await.unwrap().unwrap();
+        let under_old = store.load(&old_key).await.unwrap().unwrap();
+        assert_eq!(under_new["name"], "\"ana\"");
+        assert_eq!(under_old["name"], "\"ana\"");
+        // Written once.
+        let version = |state: &SessionState| state[VERSION_KEY].parse::<u64>().unwrap();
+        assert_eq!(version(&under_new), version(&loaded) + 1);
+    }
+
+    #[actix_web::test]
+    async fn stale_updates_are_merged_key_by_key() {
+        let store = store(SessionKeys::generate());
+        let ttl = Duration::minutes(5);
+
+        let key = store.save(state(&[("name", "\"ana\""), ("age", "30")]), &ttl).await.unwrap();
+        let first = store.load(&key).await.unwrap().unwrap();
+        let second = store.load(&key).await.unwrap().unwrap();
+
+        let mut renamed = first;
+        renamed.insert("name".into(), "\"bob\"".into());
+        let key = store.update(key, renamed, &ttl).await.unwrap();
+
+        let mut aged = second;
+        aged.insert("age".into(), "31".into());
+        let key = store.update(key, aged, &ttl).await.unwrap();
+
+        let merged = store.load(&key).await.unwrap().unwrap();
+        assert_eq!(merged["name"], "\"bob\"");
+        assert_eq!(merged["age"], "31");
+    }
+}
@@ -0,0 +1,137 @@
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue, ACCEPT, CACHE_CONTROL};
+use actix_web::{get, HttpResponse, Responder};
+use serde_json::{json, Map, Value};
+use crate::once_session::{OnceSession, FLASH_KEY, FLASH_QUEUE_KEY};
+
+/// The header flash messages are delivered in to JSON clients.
+pub const FLASH_HEADER: HeaderName = HeaderName::from_static("x-flash");
+/// The htmx header flash messages are delivered in, as a `flash` event.
+pub const HX_TRIGGER: HeaderName = HeaderName::from_static("hx-trigger");
+
+/// A client that doesn't render pages, and so needs flash messages handed over alongside the
+/// response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlashClient {
+    /// An htmx request, which gets them as an `HX-Trigger` event. Boosted requests are page
+    /// navigations, and aren't one.
+    Htmx,
+    /// A request asking for JSON (`Accept: application/json`), e.g. a `fetch` from a SPA.
+    Json,
+    /// An `EventSource` (`Accept: text/event-stream`), which gets them as events, see
+    /// `flash_stream`.
+    EventStream,
+}
+
+impl FlashClient {
+    pub fn of(headers: &HeaderMap) -> Option<Self> {
+        let is_set = |name: &str| headers.get(name).is_some_and(|value| value == "true");
+        if is_set("HX-Request") && !is_set("HX-Boosted") {
+            return Some(FlashClient::Htmx);
+        }
+
+        let accept = headers
+            .get(ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .unwrap_or_default();
+
+        if accept.contains("text/event-stream") {
+            return Some(FlashClient::EventStream);
+        }
+
+        return match accept.contains("application/json") {
+            true => Some(FlashClient::Json),
+            false => None,
+        };
+    }
+}
+
+/// The flash, enqueued flash and errors the handler hasn't read, as
+/// `{"flash": .., "queued": [..], "errors": ..}`, marking them as read. `None` when there's
+/// nothing left to deliver.
+pub fn pending(once_session: &OnceSession) -> Option<Value> {
+    let mut payload = Map::new();
+    for (key, value) in once_session.unread() {
+        let name = match key {
+            FLASH_KEY => "flash",
+            FLASH_QUEUE_KEY => "queued",
+            _ => "errors",
+        };
+
+        payload.insert(name.into(), serde_json::from_str(value).unwrap_or(Value::Null));
+    }
+
+    if payload.is_empty() {
+        return None;
+    }
+
+    once_session.flash();
+    once_session.queued();
+    once_session.errors();
+    return Some(Value::Object(payload));
+}
+
+/// `value` as a header value. Non-ASCII and control characters are escaped (they can only be in
+/// strings), so it's still the same JSON for clients decoding headers as Latin-1, and any value
+/// can be sent.
+pub fn header_json(value: &Value) -> HeaderValue {
+    let mut ascii = String::new();
+    for char in value.to_string().chars() {
+        match char.is_ascii() && !char.is_ascii_control() {
+            true => ascii.push(char),
+            false => {
+                for unit in char.encode_utf16(&mut [0; 2]) {
+                    ascii.push_str(&format!("\\u{:04x}", unit));
+                }
+            },
+        };
+    }
+
+    return HeaderValue::from_str(&ascii).expect("only visible ASCII characters are left");
+}
+
+/// Adds the `flash` event to the `HX-Trigger` events the handler may have set already.
+pub fn hx_trigger(existing: Option<&HeaderValue>, payload: Value) -> HeaderValue {
+    let existing = existing.and_then(|value| value.to_str().ok());
+    let mut events = match existing.map(serde_json::from_str::<Map<String, Value>>) {
+        None => Map::new(),
+        Some(Ok(events)) => events,
+        // A plain list of event names.
+        Some(Err(_)) => existing
+            .unwrap_or_default()
+            .split(',')
+            .map(|event| (event.trim().to_string(), Value::Null))
+            .collect(),
+    };
+
+    events.insert("flash".into(), payload);
+    return header_json(&Value::Object(events));
+}
+
+/// `GET /_flash`: drains the pending flash, enqueued flash and errors, for clients that would rather ask for
+/// them than read them off other responses.
+#[get("/_flash")]
+pub async fn drain(once_session: OnceSession) -> impl Responder {
+    let parse = |value: Option<&str>| value.and_then(|value| serde_json::from_str::<Value>(value).ok());
+
+    return HttpResponse::Ok()
+        .insert_header((CACHE_CONTROL, "no-store"))
+        .json(json!({
+            "flash": parse(once_session.flash()),
+            "queued": parse(once_session.queued()),
+            "errors": parse(once_session.errors()),
+        }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn any_flash_can_be_sent_in_a_header() {
+        let payload = json!({"flash": "caf\u{e9} \u{1f600}\tdel\u{7f}\nnul\u{0}"});
+        let value = header_json(&payload);
+
+        assert!(value.to_str().unwrap().is_ascii());
+        assert_eq!(serde_json::from_slice::<Value>(value.as_bytes()).unwrap(), payload);
+    }
+}
@@ -0,0 +1,132 @@
+use std::future::{ready, Ready};
+use actix_web::body::{self, BoxBody, EitherBody, MessageBody};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::ErrorInternalServerError;
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::{Error, HttpMessage};
+use futures_util::future::LocalBoxFuture;
+use serde_json::Value;
+use crate::flash_delivery::{header_json, hx_trigger, pending, FlashClient, FLASH_HEADER, HX_TRIGGER};
+use crate::once_session::OnceSession;
+
+/// Hands the flash, enqueued flash messages and errors the handler hasn't read over to clients
+/// that don't render pages (see `FlashClient`): htmx requests get them as a `flash` event in
+/// `HX-Trigger`, JSON ones in the `X-Flash` header or, with `merge_json`, under `_flash` in JSON
+/// object bodies. Event streams deliver their own.
+///
+/// Must be wrapped *before* `FlushOnceSessions` (so it runs after it), to find the flushed values.
+#[derive(Default)]
+pub struct DeliverFlash {
+    merge_json: bool,
+}
+
+impl DeliverFlash {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    pub fn merge_json(mut self, merge: bool) -> Self {
+        self.merge_json = merge;
+        return self;
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for DeliverFlash
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = DeliverFlashMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(DeliverFlashMiddleware { service, merge_json: self.merge_json }))
+    }
+}
+
+pub struct DeliverFlashMiddleware<S> {
+    service: S,
+    merge_json: bool,
+}
+
+impl<S, B> Service<ServiceRequest> for DeliverFlashMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let client = FlashClient::of(req.headers());
+        let once_session = req.extensions().get::<OnceSession>().cloned();
+        let fut: <S as Service<ServiceRequest>>::Future = self.service.call(req);
+        let merge_json = self.merge_json;
+
+        Box::pin(async move {
+            let mut res: ServiceResponse<B> = fut.await?;
+
+            let (Some(client), Some(once_session)) = (client, once_session) else {
+                return Ok(res.map_into_left_body());
+            };
+
+            if client == FlashClient::EventStream {
+                return Ok(res.map_into_left_body());
+            }
+
+            let Some(payload) = pending(&once_session) else {
+                return Ok(res.map_into_left_body());
+            };
+
+            tracing::info!(event = "flash.delivered", client = ?client);
+
+            if client == FlashClient::Htmx {
+                let headers = res.headers_mut();
+                let value = hx_trigger(headers.get(HX_TRIGGER), payload);
+                headers.insert(HX_TRIGGER, value);
+
+                return Ok(res.map_into_left_body());
+            }
+
+            let is_json = res.headers()
+                .get(CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .is_some_and(|value| value.starts_with("application/json"));
+
+            if !(merge_json && is_json) {
+                res.headers_mut().insert(FLASH_HEADER, header_json(&payload));
+
+                return Ok(res.map_into_left_body());
+            }
+
+            let (req, res) = res.into_parts();
+            let (mut res, body) = res.into_parts();
+            let bytes = body::to_bytes(body)
+                .await
+                .map_err(|err| ErrorInternalServerError(err.into()))?;
+
+            let body = match serde_json::from_slice::<Value>(&bytes) {
+                Ok(Value::Object(mut object)) => {
+                    object.insert("_flash".into(), payload);
+                    Value::Object(object).to_string().into_bytes().into()
+                },
+                // Only objects can take it in: anything else gets it in the header.
+                _ => {
+                    res.headers_mut().insert(FLASH_HEADER, header_json(&payload));
+                    bytes
+                },
+            };
+
+            let res = res.set_body(BoxBody::new(body));
+            return Ok(ServiceResponse::new(req, res).map_into_right_body());
+        })
+    }
+}
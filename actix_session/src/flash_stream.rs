@@ -0,0 +1,61 @@
+use std::pin::pin;
+use std::time::Duration;
+use actix_web::error::ErrorUnauthorized;
+use actix_web::http::header::CACHE_CONTROL;
+use actix_web::rt::time::{interval, Interval};
+use actix_web::web::{Bytes, Data};
+use actix_web::{get, Error, HttpResponse};
+use futures_util::future::{select, Either};
+use futures_util::{stream, StreamExt};
+use serde_json::Value;
+use tokio::sync::mpsc::Receiver;
+use crate::flash_delivery::pending;
+use crate::once_session::OnceSession;
+use crate::stateful_session::StatefulSessions;
+use crate::trace_middleware::current_session;
+
+/// How often an idle stream gets a comment, so that proxies don't close it.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+fn event(payload: &Value) -> Bytes {
+    return Bytes::from(format!("event: flash\ndata: {}\n\n", payload));
+}
+
+/// Waits for the next pushed message, sending a comment whenever the stream has been idle for
+/// too long. Ends once the session is gone.
+async fn next_event(
+    (mut messages, mut keep_alive): (Receiver<Value>, Interval),
+) -> Option<(Result<Bytes, Error>, (Receiver<Value>, Interval))> {
+    let next = match select(pin!(messages.recv()), pin!(keep_alive.tick())).await {
+        Either::Left((Some(payload), _)) => Some(event(&payload)),
+        Either::Left((None, _)) => None,
+        Either::Right(_) => Some(Bytes::from_static(b": keep-alive\n\n")),
+    };
+
+    return next.map(|bytes| (Ok(bytes), (messages, keep_alive)));
+}
+
+/// `GET /_flash/stream`: streams, as Server-Sent Events named `flash`, the flash messages pushed
+/// to the session the request comes with (see `StatefulSessions::push_flash`), starting with the
+/// ones it has pending. Without a stored session, it's a `401`. The stream ends along with the
+/// session, and a reconnecting `EventSource` picks up the new one.
+#[get("/_flash/stream")]
+pub async fn subscribe(
+    once_session: OnceSession,
+    store: Data<StatefulSessions>,
+) -> Result<HttpResponse, Error> {
+    let hashed_key = current_session()
+        .filter(|hashed_key| store.inspect(hashed_key).is_some())
+        .ok_or_else(|| ErrorUnauthorized("No session to stream the flash messages of."))?;
+
+    let first = pending(&once_session).map(|payload| event(&payload));
+    let messages = store.watch(&hashed_key);
+    tracing::info!(event = "flash.stream_opened", session = %hashed_key);
+
+    let pushed = stream::unfold((messages, interval(KEEP_ALIVE)), next_event);
+    let events = stream::iter(first.map(Ok)).chain(pushed);
+    return Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        .streaming(events));
+}
@@ -0,0 +1,140 @@
+use std::future::{ready, Ready};
+use actix_session::{Session, SessionExt, SessionInsertError};
+use actix_web::error::ErrorUnauthorized;
+use actix_web::http::header::USER_AGENT;
+use actix_web::{FromRequest, HttpRequest};
+use crate::once_session::OnceSessionExt;
+
+/// The session entry holding the signed in user's id.
+pub const USER_KEY: &str = "_user_id";
+/// The session entry holding the signed in user's roles.
+pub const ROLES_KEY: &str = "_user_roles";
+/// The session entry holding the user agent the user signed in from.
+pub const USER_AGENT_KEY: &str = "_user_agent";
+/// Every session entry that makes a session signed in.
+pub const IDENTITY_KEYS: [&str; 3] = [USER_KEY, ROLES_KEY, USER_AGENT_KEY];
+
+/// The user signed in on the current session. Extracting it fails with `401 Unauthorized` when
+/// nobody is signed in; extract an `Option<Identity>` for routes open to everyone.
+pub struct Identity {
+    session: Session,
+    user_id: String,
+    roles: Vec<String>,
+}
+
+impl Identity {
+    /// Signs `user_id` in on the request's session, holding `roles`. The session key is
+    /// regenerated first, so a key planted before the login (session fixation) never gets to be
+    /// authenticated.
+    pub fn login(
+        req: &HttpRequest,
+        user_id: impl Into<String>,
+        roles: Vec<String>,
+    ) -> Result<Self, SessionInsertError> {
+        let session = req.get_session();
+        let user_id = user_id.into();
+        session.regenerate();
+        session.insert(USER_KEY, &user_id)?;
+        session.insert(ROLES_KEY, &roles)?;
+
+        let user_agent = req.headers()
+            .get(USER_AGENT)
+            .and_then(|value| value.to_str().ok());
+
+        if let Some(user_agent) = user_agent {
+            session.insert(USER_AGENT_KEY, user_agent)?;
+        }
+
+        return Ok(Identity { session, user_id, roles });
+    }
+
+    /// The user signed in on `session`, if any.
+    pub fn of(session: &Session) -> Option<Self> {
+        let user_id = session.get::<String>(USER_KEY).ok()??;
+        let roles = session.get::<Vec<String>>(ROLES_KEY).ok().flatten().unwrap_or_default();
+
+        return Some(Identity { session: session.clone(), user_id, roles });
+    }
+
+    pub fn id(&self) -> &str {
+        return &self.user_id;
+    }
+
+    pub fn has_role(&self, role: &str) -> bool {
+        return self.roles.iter().any(|granted| granted == role);
+    }
+
+    /// Signs the user out, destroying their session in the store. The response carries a new,
+    /// empty session instead, so that a flash can be sent along.
+    pub fn logout(self) {
+        self.session.clear();
+        self.session.renew();
+    }
+}
+
+impl FromRequest for Identity {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
+        let identity = Identity::of(&req.get_session())
+            .ok_or_else(|| ErrorUnauthorized("Not signed in."));
+
+        return ready(identity);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use actix_session::SessionMiddleware;
+    use actix_web::cookie::Key;
+    use actix_web::{test, web, App, HttpResponse};
+    use crate::session_hash::HashedKey;
+    use crate::session_hooks::SessionEvent;
+    use crate::stateful_session::StatefulSessions;
+    use super::*;
+
+    #[actix_web::test]
+    async fn logout_destroys_the_stored_session() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        let store = StatefulSessions::new().hook(move |hashed_key: &HashedKey, event| {
+            recorded.lock().unwrap().push((hashed_key.clone(), event));
+        });
+        let user_id = uuid::Uuid::new_v4().to_string();
+
+        let app = test::init_service(
+            App::new()
+                .wrap(SessionMiddleware::new(store.clone(), Key::generate()))
+                .app_data(web::Data::new(user_id.clone()))
+                .route("/login", web::post().to(|req: HttpRequest, user_id: web::Data<String>| async move {
+                    Identity::login(&req, user_id.as_str(), Vec::new()).unwrap();
+                    return HttpResponse::Ok().finish();
+                }))
+                .route("/logout", web::post().to(|identity: Identity| async move {
+                    identity.logout();
+                    return HttpResponse::Ok().finish();
+                })),
+        ).await;
+
+        let res = test::call_service(&app, test::TestRequest::post().uri("/login").to_request()).await;
+        let cookie = res.response().cookies().next().unwrap().into_owned();
+        let [session] = store.user_sessions(&user_id).try_into().unwrap();
+
+        let req = test::TestRequest::post().uri("/logout").cookie(cookie.clone()).to_request();
+        let res = test::call_service(&app, req).await;
+        let new_cookie = res.response().cookies().next().unwrap().into_owned();
+
+        assert_ne!(new_cookie.value(), cookie.value());
+        assert!(store.user_sessions(&user_id).is_empty());
+        let old_events = events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(hashed_key, _)| *hashed_key == session.hashed_key)
+            .map(|(_, event)| *event)
+            .collect::<Vec<_>>();
+        assert_eq!(old_events.last(), Some(&SessionEvent::Destroyed));
+    }
+}
@@ -0,0 +1,216 @@
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use actix_session::config::CookieContentSecurity;
+use actix_web::cookie::{Cookie, CookieJar};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderValue, COOKIE, SET_COOKIE};
+use actix_web::Error;
+use futures_util::future::LocalBoxFuture;
+use crate::session_cookie::SessionCookie;
+use crate::session_keys::SessionKeys;
+
+/// Accepts session cookies signed under any of the retired keys by re-signing them under the
+/// active key before `SessionMiddleware` gets to verify them. The re-signed cookie is sent back
+/// on the response, unless `SessionMiddleware` has already set a new one.
+///
+/// Must be wrapped *after* `SessionMiddleware` (so it runs first), and given the `SessionCookie`
+/// `SessionMiddleware` has been built from, so the re-signed cookie keeps its attributes.
+pub struct RotateSessionKeys {
+    keys: Rc<SessionKeys>,
+    cookie: Rc<SessionCookie>,
+}
+
+impl RotateSessionKeys {
+    pub fn new(keys: SessionKeys, cookie: SessionCookie) -> Self {
+        return RotateSessionKeys { keys: Rc::new(keys), cookie: Rc::new(cookie) };
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RotateSessionKeys
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RotateSessionKeysMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RotateSessionKeysMiddleware {
+            service,
+            keys: Rc::clone(&self.keys),
+            cookie: Rc::clone(&self.cookie),
+        }))
+    }
+}
+
+pub struct RotateSessionKeysMiddleware<S> {
+    service: S,
+    keys: Rc<SessionKeys>,
+    cookie: Rc<SessionCookie>,
+}
+
+impl<S> RotateSessionKeysMiddleware<S> {
+    /// Returns the session cookie value verified by the first key that accepts it, or `None` if
+    /// the active key already does (or no key does at all).
+    fn verify_with_retired(&self, cookie: &Cookie<'static>) -> Option<String> {
+        let mut jar = CookieJar::new();
+        jar.add_original(cookie.clone());
+
+        if self.verify(&jar, self.keys.active()).is_some() {
+            return None;
+        }
+
+        return self.keys
+            .retired()
+            .iter()
+            .find_map(|key| self.verify(&jar, key));
+    }
+
+    fn verify(&self, jar: &CookieJar, key: &actix_web::cookie::Key) -> Option<String> {
+        let name = self.cookie.cookie_name();
+        let cookie = match self.cookie.cookie_content_security() {
+            CookieContentSecurity::Signed => jar.signed(key).get(name),
+            CookieContentSecurity::Private => jar.private(key).get(name),
+        };
+
+        return cookie.map(|cookie| cookie.value().to_string());
+    }
+
+    fn sign(&self, value: String) -> Cookie<'static> {
+        let mut jar = CookieJar::new();
+        let cookie = self.cookie.build(value);
+        match self.cookie.cookie_content_security() {
+            CookieContentSecurity::Signed => jar.signed_mut(self.keys.active()).add(cookie),
+            CookieContentSecurity::Private => jar.private_mut(self.keys.active()).add(cookie),
+        };
+
+        return jar.get(self.cookie.cookie_name()).unwrap().clone();
+    }
+
+    /// Rewrites the request's `Cookie` header so the session cookie is signed under the active
+    /// key. Returns the re-signed cookie, if any.
+    ///
+    /// The header is parsed by hand: `HttpRequest::cookies` caches its result, and
+    /// `SessionMiddleware` must see the rewritten header. Values are percent-encoded, as
+    /// `SessionMiddleware` encodes them.
+    fn resign_request_cookie(&self, req: &mut ServiceRequest) -> Option<Cookie<'static>> {
+        let mut cookies = req.headers()
+            .get_all(COOKIE)
+            .filter_map(|header| header.to_str().ok())
+            .flat_map(|header| header.split(';'))
+            .filter_map(|cookie| Cookie::parse_encoded(cookie.trim().to_string()).ok())
+            .collect::<Vec<_>>();
+
+        let session_cookie = cookies
+            .iter_mut()
+            .find(|cookie| cookie.name() == self.cookie.cookie_name())?;
+
+        let resigned = self.sign(self.verify_with_retired(session_cookie)?);
+        session_cookie.set_value(resigned.value().to_string());
+
+        let header = cookies
+            .iter()
+            .map(|cookie| cookie.encoded().stripped().to_string())
+            .collect::<Vec<_>>()
+            .join("; ");
+
+        req.headers_mut().insert(COOKIE, HeaderValue::from_str(&header).ok()?);
+        return Some(resigned);
+    }
+}
+
+impl<S, B> Service<ServiceRequest> for RotateSessionKeysMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let resigned = self.resign_request_cookie(&mut req);
+        let fut: <S as Service<ServiceRequest>>::Future = self.service.call(req);
+
+        Box::pin(async move {
+            let mut res: ServiceResponse<B> = fut.await?;
+
+            if let Some(cookie) = resigned {
+                let already_set = res.response()
+                    .cookies()
+                    .any(|set_cookie| set_cookie.name() == cookie.name());
+
+                if !already_set {
+                    match HeaderValue::from_str(&cookie.encoded().to_string()) {
+                        Ok(value) => res.response_mut().headers_mut().append(SET_COOKIE, value),
+                        Err(err) => tracing::error!(event = "cookie.resign_failed", error = %err),
+                    }
+                }
+            }
+
+            return Ok(res);
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_session::storage::CookieSessionStore;
+    use actix_session::Session;
+    use actix_web::cookie::time::Duration;
+    use actix_web::cookie::{Key, SameSite};
+    use actix_web::{test, web, App, HttpResponse};
+    use super::*;
+
+    #[actix_web::test]
+    async fn resigned_cookies_keep_the_session_cookie_attributes() {
+        let cookie = SessionCookie::new()
+            .name("session")
+            .path("/app")
+            .domain("example.com")
+            .secure(false)
+            .same_site(SameSite::Strict)
+            .persistent(Duration::days(7));
+        let retired = Key::generate();
+
+        let old_app = test::init_service(
+            App::new()
+                .wrap(cookie.middleware(CookieSessionStore::default(), retired.clone()))
+                .route("/app", web::get().to(|session: Session| async move {
+                    session.insert("user", "alice").unwrap();
+                    return HttpResponse::Ok().finish();
+                })),
+        ).await;
+        let res = test::call_service(&old_app, test::TestRequest::get().uri("/app").to_request()).await;
+        let old_cookie = res.response().cookies().next().unwrap().into_owned();
+
+        let keys = SessionKeys::new(Key::generate(), vec![retired]);
+        let app = test::init_service(
+            App::new()
+                .wrap(cookie.middleware(CookieSessionStore::default(), keys.active().clone()))
+                .wrap(RotateSessionKeys::new(keys, cookie.clone()))
+                .route("/app", web::get().to(|session: Session| async move {
+                    return HttpResponse::Ok().body(session.get::<String>("user").unwrap().unwrap_or_default());
+                })),
+        ).await;
+        let req = test::TestRequest::get().uri("/app").cookie(old_cookie.clone()).to_request();
+        let res = test::call_service(&app, req).await;
+
+        let resigned = res.response().cookies().next().unwrap().into_owned();
+        assert_ne!(resigned.value(), old_cookie.value());
+        assert_eq!(resigned.path(), Some("/app"));
+        assert_eq!(resigned.domain(), Some("example.com"));
+        assert!(!resigned.secure().unwrap_or(false));
+        assert_eq!(resigned.http_only(), Some(true));
+        assert_eq!(resigned.same_site(), Some(SameSite::Strict));
+        assert_eq!(resigned.max_age(), Some(Duration::days(7)));
+        assert_eq!(test::read_body(res).await, "alice");
+    }
+}
@@ -1,32 +1,72 @@
-use once_session::{OnceSession, OnceSessionExt};
+#![allow(clippy::needless_return, clippy::upper_case_acronyms)]
+
+use once_session::{OnceSession, OnceSessionExt, FLASH_QUEUE_KEY, REGENERATE_KEY};
 use std::collections::HashMap;
 use std::io;
-use actix_session::{Session, SessionMiddleware};
-use actix_web::{get, App, HttpResponse, HttpServer, Responder};
+use std::sync::Arc;
+use actix_session::config::CookieContentSecurity;
+use actix_session::Session;
+use actix_web::{get, post, App, Either, HttpRequest, HttpResponse, HttpServer, Responder};
 use actix_web::body::BoxBody;
-use actix_web::cookie::Key;
+use actix_web::cookie::time::Duration;
+use actix_web::cookie::SameSite;
 use actix_web::dev::ServiceResponse;
 use actix_web::http::{header::ContentType, StatusCode};
-use actix_web::middleware::{ErrorHandlerResponse, ErrorHandlers};
+use actix_web::middleware::{Condition, ErrorHandlerResponse, ErrorHandlers};
+use actix_web::guard;
 use actix_web::web::{self, Data, Html, Redirect};
+use auth_middleware::{RequireLogin, RequireRole};
+use csrf::CsrfToken;
+use csrf_middleware::VerifyCsrf;
+use encrypted_session_store::EncryptedSessions;
+use flash_delivery_middleware::DeliverFlash;
 use handlebars::{DirectorySourceOptions, Handlebars};
+use identity::{Identity, USER_AGENT_KEY, USER_KEY};
+use key_rotation_middleware::RotateSessionKeys;
 use once_sessions_middleware::FlushOnceSessions;
+use problem::ValidationErrors;
+use rate_limit::{FixedWindowLimiter, SessionRateLimiter};
+use rate_limit_middleware::LimitSessionCreation;
+use serde::Deserialize;
 use serde_json::json;
-use stateful_session::StatefulSessions;
+use session_cookie::SessionCookie;
+use session_keys::SessionKeys;
+use session_hash::HashedKey;
+use session_hooks::SessionEvent;
+use stateful_session::{ConflictPolicy, StatefulSessions, QUEUED_UP_TO_KEY, VERSION_KEY};
+use trace_middleware::TraceRequests;
+use tracing_subscriber::EnvFilter;
 
 mod stateful_session;
 mod once_sessions_middleware;
 mod once_session;
+mod session_keys;
+mod session_cookie;
+mod key_rotation_middleware;
+mod encrypted_session_store;
+mod session_hash;
+mod session_hooks;
+mod rate_limit;
+mod rate_limit_middleware;
+mod csrf;
+mod csrf_middleware;
+mod identity;
+mod auth_middleware;
+mod admin;
+mod metrics;
+mod trace_middleware;
+mod flash_delivery;
+mod flash_delivery_middleware;
+mod flash_stream;
+mod problem;
+
+pub type HBS<'a> = Data<Handlebars<'a>>;
 
-type HBS<'a> = Data<Handlebars<'a>>;
+const SESSION_LIFETIME: Duration = Duration::hours(12);
 
 #[get("/backwitherrors")]
-async fn back_with_errors(session: Session, once_session: OnceSession) -> impl Responder {
-    if let Err(err) = session.insert_errors(json!({"name": "Your name is too ugly!".to_string()})) {
-        eprintln!("{}", err);
-    };
-    
-    return Redirect::to(once_session.prev_req).using_status_code(StatusCode::SEE_OTHER);
+async fn back_with_errors() -> impl Responder {
+    return ValidationErrors::new(json!({"name": "Your name is too ugly!"}));
 }
 
 #[get("/foo")]
@@ -59,8 +99,148 @@ async fn redirect(session: Session) -> impl Responder {
     return Redirect::new("/redirect", "/foo");
 }
 
+#[derive(Deserialize)]
+struct Greeting {
+    name: String,
+}
+
+#[post("/greet")]
+async fn greet(
+    session: Session,
+    once_session: OnceSession,
+    greeting: web::Form<Greeting>,
+) -> impl Responder {
+    if greeting.name.trim().is_empty() {
+        return Either::Left(ValidationErrors::new(json!({"name": "Please tell us your name."})));
+    }
+
+    if let Err(err) = session.insert_flash(format!("Hello, {}!", greeting.name)) {
+        tracing::warn!(event = "session.insert_failed", key = "_flash", error = %err);
+    };
+
+    return Either::Right(Redirect::to(once_session.prev_req).using_status_code(StatusCode::SEE_OTHER));
+}
+
+#[derive(Deserialize)]
+struct Login {
+    user_id: String,
+}
+
+#[post("/login")]
+async fn login(req: HttpRequest, session: Session, form: web::Form<Login>) -> impl Responder {
+    // The login form takes anyone's word for who they are, so it never grants a role.
+    match Identity::login(&req, form.into_inner().user_id, Vec::new()) {
+        Ok(identity) => {
+            tracing::info!(event = "identity.login", user = identity.id());
+            if let Err(err) = session.insert_flash(format!("Welcome back, {}!", identity.id())) {
+                tracing::warn!(event = "session.insert_failed", key = "_flash", error = %err);
+            }
+        },
+        Err(err) => tracing::error!(event = "identity.login_failed", error = %err),
+    };
+
+    return session.redirect_intended("/");
+}
+
+#[get("")]
+async fn account(identity: Identity) -> impl Responder {
+    return HttpResponse::Ok()
+        .content_type(ContentType::plaintext())
+        .body(format!("Signed in as {}.", identity.id()));
+}
+
+#[post("/logout")]
+async fn logout(session: Session, identity: Option<Identity>) -> impl Responder {
+    if let Some(identity) = identity {
+        identity.logout();
+        if let Err(err) = session.insert_flash("You have been signed out.") {
+            tracing::warn!(event = "session.insert_failed", key = "_flash", error = %err);
+        }
+    }
+
+    return Redirect::to("/").using_status_code(StatusCode::SEE_OTHER);
+}
+
+#[get("/sessions")]
+async fn account_sessions(identity: Identity, store: Data<StatefulSessions>) -> impl Responder {
+    let sessions = store
+        .user_sessions(identity.id())
+        .into_iter()
+        .map(|session| json!({
+            "id": session.hashed_key.to_string(),
+            "created_at": session.created_at.unix_timestamp(),
+            "last_seen": session.last_seen.unix_timestamp(),
+            "user_agent": session.user_agent,
+        }))
+        .collect::<Vec<_>>();
+
+    return HttpResponse::Ok().json(sessions);
+}
+
+#[derive(Deserialize)]
+struct RevokeSession {
+    id: String,
+}
+
+#[post("/sessions/revoke")]
+async fn revoke_session(
+    session: Session,
+    identity: Identity,
+    store: Data<StatefulSessions>,
+    form: web::Form<RevokeSession>,
+) -> impl Responder {
+    let revoked = HashedKey::parse(&form.id)
+        .is_some_and(|hashed_key| store.revoke_user_session(identity.id(), &hashed_key));
+
+    let flash = match revoked {
+        true => "The session has been signed out.",
+        false => "There is no such session.",
+    };
+
+    if let Err(err) = session.insert_flash(flash) {
+        tracing::warn!(event = "session.insert_failed", key = "_flash", error = %err);
+    }
+
+    return Redirect::to("/").using_status_code(StatusCode::SEE_OTHER);
+}
+
+#[post("/sessions/revoke-all")]
+async fn revoke_all_sessions(identity: Identity, store: Data<StatefulSessions>) -> impl Responder {
+    store.revoke_user_sessions(identity.id());
+    return Redirect::to("/").using_status_code(StatusCode::SEE_OTHER);
+}
+
+/// Pretends to run a long export, and tells the user's open pages once it's done, or their next
+/// request if none is open.
+#[post("/export")]
+async fn export(identity: Identity, store: Data<StatefulSessions>) -> impl Responder {
+    let user_id = identity.id().to_string();
+    actix_web::rt::spawn(async move {
+        actix_web::rt::time::sleep(std::time::Duration::from_secs(3)).await;
+        let message = "Your export is ready.";
+        if store.push_flash_to_user(&user_id, message) == 0 {
+            store.enqueue_flash_to_user(&user_id, message);
+        }
+    });
+
+    return HttpResponse::Accepted().finish();
+}
+
+#[get("/metrics")]
+async fn metrics_endpoint(store: Data<StatefulSessions>) -> impl Responder {
+    let (live_sessions, bytes_stored) = store.stored();
+    return HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::METRICS.render(live_sessions, bytes_stored));
+}
+
 #[get("/")]
-async fn index(hb: HBS<'_>, once_session: OnceSession) -> impl Responder {
+async fn index(
+    hb: HBS<'_>,
+    once_session: OnceSession,
+    csrf_token: CsrfToken,
+    identity: Option<Identity>,
+) -> impl Responder {
     let sessions = once_session
         .map::<String, HashMap<String, String>>()
         .unwrap();
@@ -69,7 +249,10 @@ async fn index(hb: HBS<'_>, once_session: OnceSession) -> impl Responder {
         .render("index", &json!({
             "title": "Home!",
             "errors": sessions.errors,
-            "flash": sessions.flash
+            "flash": sessions.flash,
+            "queued": sessions.queued,
+            "csrf_token": csrf_token,
+            "user_id": identity.as_ref().map(Identity::id)
         }))
         .map(Html::new)
         .unwrap();
@@ -77,27 +260,127 @@ async fn index(hb: HBS<'_>, once_session: OnceSession) -> impl Responder {
 
 #[actix_web::main]
 async fn main() -> io::Result<()> {
+    init_tracing();
+
     let mut handlebars = Handlebars::new();
     handlebars
         .register_templates_directory("./www", DirectorySourceOptions::default())
         .unwrap();
+    handlebars.register_helper("csrf_field", Box::new(csrf::csrf_field));
 
     let handlebars_ref = web::Data::new(handlebars);
 
-    let secret = Key::generate();
+    let keys = SessionKeys::from_env("SESSION_KEY")
+        .map_err(io::Error::other)?
+        .unwrap_or_else(|| {
+            tracing::warn!("SESSION_KEY is not set, generating an ephemeral key for this run.");
+            SessionKeys::generate()
+        });
+
+    // Session states are only sealed when a key has been configured for them.
+    let state_keys = SessionKeys::from_env("SESSION_STATE_KEY").map_err(io::Error::other)?;
+
+    let conflict_policy = std::env::var("SESSION_CONFLICT_POLICY")
+        .ok()
+        .map(|policy| policy.parse::<ConflictPolicy>())
+        .transpose()
+        .map_err(io::Error::other)?
+        .unwrap_or_default();
+
+    let creation_limiter: Arc<dyn SessionRateLimiter> = Arc::new(
+        FixedWindowLimiter::new(20, std::time::Duration::from_secs(60))
+    );
+
+    // Only set it behind a proxy that overwrites the forwarded-for headers.
+    let trust_forwarded_for = std::env::var("TRUST_FORWARDED_FOR").is_ok_and(|trust| trust == "true");
+
+    // The sessions admin is only mounted when a token is set for it.
+    let admin_token = std::env::var("ADMIN_TOKEN").ok().filter(|token| !token.is_empty());
+
+    // `SessionMiddleware`, `RotateSessionKeys` and `LimitSessionCreation` must all agree on it. It
+    // lasts as long as the sessions' absolute lifetime, and is only sent over HTTPS unless
+    // `SESSION_COOKIE_SECURE` is `false`.
+    let mut cookie = SessionCookie::new()
+        .name("session")
+        .content_security(CookieContentSecurity::Private)
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .secure(!std::env::var("SESSION_COOKIE_SECURE").is_ok_and(|secure| secure == "false"))
+        .persistent(SESSION_LIFETIME);
+    if let Ok(domain) = std::env::var("SESSION_COOKIE_DOMAIN") {
+        cookie = cookie.domain(domain);
+    }
 
     HttpServer::new(move || {
+        let store = sessions_store(creation_limiter.clone(), conflict_policy);
+        // Reached with the token in the `X-Admin-Token` header, or signed in with it on the admin
+        // login form.
+        let admin = admin_token.clone().map(|token| {
+            let admin_guard = guard::Any(admin::AdminToken::new(token.clone()))
+                .or(RequireRole::new(admin::ADMIN_ROLE, "/admin/login"));
+
+            (
+                admin::scope("/admin/sessions", admin_guard),
+                admin::login_scope("/admin/login", admin::AdminToken::new(token)),
+            )
+        });
+
+        // Requests authenticated by the token header can't be forged by other sites.
+        let mut verify_csrf = VerifyCsrf::new();
+        if let Some(token) = admin_token.clone() {
+            verify_csrf = verify_csrf.exempt(admin::AdminToken::new(token));
+        }
+
         App::new()
             .wrap(error_handlers())
-            .wrap(FlushOnceSessions)
-            .wrap(SessionMiddleware::new(StatefulSessions, secret.clone()))
+            .wrap(verify_csrf)
+            .wrap(DeliverFlash::new().merge_json(true))
+            .wrap(FlushOnceSessions::new().keep_unread(true))
+            .wrap(Condition::new(
+                state_keys.is_none(),
+                cookie.middleware(store.clone(), keys.active().clone()),
+            ))
+            .wrap(Condition::new(
+                state_keys.is_some(),
+                cookie.middleware(
+                    sealed_sessions_store(store.clone(), state_keys.clone().unwrap_or_else(SessionKeys::generate)),
+                    keys.active().clone(),
+                ),
+            ))
+            .wrap(RotateSessionKeys::new(keys.clone(), cookie.clone()))
+            .wrap(
+                LimitSessionCreation::new()
+                    .cookie_name(cookie.cookie_name())
+                    .trust_forwarded_for(trust_forwarded_for)
+            )
+            .wrap(TraceRequests)
             .app_data(handlebars_ref.clone())
+            .app_data(Data::new(store))
             .service(index)
             .service(foo)
             .service(redirect)
             .service(redirect_to_forward)
             .service(forward_session)
             .service(back_with_errors)
+            .service(greet)
+            .service(metrics_endpoint)
+            .service(flash_delivery::drain)
+            .service(flash_stream::subscribe)
+            .service(login)
+            .service(logout)
+            .configure(|cfg| if let Some((admin, admin_login)) = admin {
+                cfg.service(admin).service(admin_login);
+            })
+            .service(
+                web::scope("/account")
+                    .wrap(RequireLogin::new("/"))
+                    .service(account)
+                    .service(account_sessions)
+                    .service(revoke_session)
+                    .service(revoke_all_sessions)
+                    .service(export)
+            )
             .service(actix_files::Files::new("/", "./public/").prefer_utf8(true))
     })
     .workers(2)
@@ -106,6 +389,49 @@ async fn main() -> io::Result<()> {
     .await
 }
 
+/// Logs to stdout, filtered by `RUST_LOG` (`info` by default), as JSON lines when `LOG_FORMAT` is
+/// `json`.
+fn init_tracing() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+
+    match std::env::var("LOG_FORMAT").as_deref() {
+        Ok("json") => subscriber.json().init(),
+        _ => subscriber.init(),
+    };
+}
+
+fn sessions_store(
+    creation_limiter: Arc<dyn SessionRateLimiter>,
+    conflict_policy: ConflictPolicy,
+) -> StatefulSessions {
+    return StatefulSessions::new()
+        .idle_timeout(Duration::minutes(30))
+        .absolute_lifetime(SESSION_LIFETIME)
+        .conflict_policy(conflict_policy)
+        .rotate_keys(Duration::minutes(15), Duration::seconds(30))
+        // An audit trail of the sessions ending, apart from the request logs (`RUST_LOG=audit=info`).
+        .hook(|hashed_key: &HashedKey, event: SessionEvent| {
+            if matches!(event, SessionEvent::Expired | SessionEvent::Destroyed | SessionEvent::Evicted) {
+                tracing::info!(target: "audit", event = event.name(), session = %hashed_key);
+            }
+        })
+        .limit_creation(creation_limiter)
+        .max_sessions_per_user(5);
+}
+
+/// Seals `store`'s states, apart from what it reads itself: its bookkeeping, who the session
+/// belongs to (to list, revoke and cap their sessions), and the flash messages it enqueues.
+fn sealed_sessions_store(store: StatefulSessions, state_keys: SessionKeys) -> EncryptedSessions<StatefulSessions> {
+    return EncryptedSessions::new(store, state_keys)
+        .reveal(VERSION_KEY)
+        .reveal(REGENERATE_KEY)
+        .reveal(FLASH_QUEUE_KEY)
+        .reveal(QUEUED_UP_TO_KEY)
+        .reveal(USER_KEY)
+        .reveal(USER_AGENT_KEY);
+}
+
 fn error_handlers() -> ErrorHandlers<BoxBody> {
     ErrorHandlers::new().handler(StatusCode::NOT_FOUND, not_found)
 }
@@ -1,64 +1,137 @@
+use std::cell::Cell;
 use std::fmt::Debug;
+use std::rc::Rc;
 
 use actix_session::{Session, SessionInsertError};
-use actix_web::{dev::ServiceRequest, FromRequest, HttpMessage, HttpRequest};
-use serde::{de::DeserializeOwned, Serialize};
+use actix_web::{dev::ServiceRequest, web::Redirect, FromRequest, HttpMessage, HttpRequest};
+use actix_web::http::StatusCode;
+use serde::{de::DeserializeOwned, ser::SerializeStruct, Serialize};
+use crate::csrf::CSRF_TOKEN_KEY;
+use crate::flash_delivery::FlashClient;
+use crate::metrics::METRICS;
+use crate::stateful_session::VERSION_KEY;
 
-#[derive(Default, Clone, Serialize)]
+/// Which of the flushed values have been read, shared by all the clones of a `OnceSession`.
+#[derive(Default)]
+struct Reads {
+    flash: Cell<bool>,
+    queued: Cell<bool>,
+    errors: Cell<bool>,
+}
+
+/// The flash and errors the previous request has left, along with the flash messages enqueued
+/// from outside a request (see `StatefulSessions::enqueue_flash`), flushed out of the session by
+/// `FlushOnceSessions`. Reading them (through `flash`, `errors`, `map` or by serializing it into
+/// a template) marks them as read, so that the middleware can tell the ones that got lost.
+#[derive(Default, Clone)]
 pub struct OnceSession {
-    pub flash: Option<String>,
-    pub errors: Option<String>,
-    pub prev_req: String
+    flash: Option<String>,
+    /// The enqueued flash messages, as a JSON array.
+    queued: Option<String>,
+    errors: Option<String>,
+    pub prev_req: String,
+    /// The keys of the values a previous request already left unread, and kept.
+    kept: Vec<String>,
+    reads: Rc<Reads>,
+}
+
+impl Serialize for OnceSession {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("OnceSession", 4)?;
+        state.serialize_field("flash", &self.flash())?;
+        state.serialize_field("queued", &self.queued())?;
+        state.serialize_field("errors", &self.errors())?;
+        state.serialize_field("prev_req", &self.prev_req)?;
+        return state.end();
+    }
 }
 
 #[derive(Serialize)]
 pub struct OnceSessionMapped<F, E> {
     pub flash: Option<F>,
+    pub queued: Vec<F>,
     pub errors: Option<E>
 }
 
 impl OnceSession {
+    /// The flash, as JSON. Marks it as read.
+    pub fn flash(&self) -> Option<&str> {
+        self.reads.flash.set(true);
+        return self.flash.as_deref();
+    }
+
+    /// The enqueued flash messages, as a JSON array. Marks them as read.
+    pub fn queued(&self) -> Option<&str> {
+        self.reads.queued.set(true);
+        return self.queued.as_deref();
+    }
+
+    /// The errors, as JSON. Marks them as read.
+    pub fn errors(&self) -> Option<&str> {
+        self.reads.errors.set(true);
+        return self.errors.as_deref();
+    }
+
+    pub fn has_flash(&self) -> bool {
+        return self.flash.is_some();
+    }
+
+    pub fn is_flash_read(&self) -> bool {
+        return self.reads.flash.get();
+    }
+
+    /// The values nobody has read, as their session key and JSON.
+    pub fn unread(&self) -> Vec<(&'static str, &str)> {
+        let mut unread = Vec::new();
+        if let (Some(flash), false) = (&self.flash, self.reads.flash.get()) {
+            unread.push((FLASH_KEY, flash.as_str()));
+        }
+
+        if let (Some(queued), false) = (&self.queued, self.reads.queued.get()) {
+            unread.push((FLASH_QUEUE_KEY, queued.as_str()));
+        }
+
+        if let (Some(errors), false) = (&self.errors, self.reads.errors.get()) {
+            unread.push((ERRORS_KEY, errors.as_str()));
+        }
+
+        return unread;
+    }
+
+    /// Whether the value under `key` has already been kept once for being left unread.
+    pub fn was_kept(&self, key: &str) -> bool {
+        return self.kept.iter().any(|kept| kept == key);
+    }
+
     pub fn map<F, E>(&self) -> Result<OnceSessionMapped<F, E>, anyhow::Error>
     where F: DeserializeOwned + Debug, E: DeserializeOwned + Debug
     {
-        let flash = self.flash
-            .as_deref()
+        let flash = self.flash()
             .map(serde_json::from_str::<F>)
-            .map(|v| {
-                v.map_err(|err | anyhow::Error::from(err))
-            });
+            .map(|v| v.map_err(anyhow::Error::from));
 
         let flash = match flash {
             None => None,
-            Some(parse_result) => {
-                if parse_result.is_err() {
-                    return Err(parse_result.unwrap_err());
-                }
+            Some(parse_result) => Some(parse_result?),
+        };
 
-                Some(parse_result.unwrap())
-            },
+        let queued = match self.queued() {
+            None => Vec::new(),
+            Some(queued) => serde_json::from_str::<Option<Vec<F>>>(queued)?.unwrap_or_default(),
         };
 
-        let errors = self.errors
-            .as_deref()
+        let errors = self.errors()
             .map(serde_json::from_str::<E>)
-            .map(|v| {
-                v.map_err(|err | anyhow::Error::from(err))
-            });
+            .map(|v| v.map_err(anyhow::Error::from));
 
         let errors = match errors {
             None => None,
-            Some(parse_result) => {
-                if parse_result.is_err() {
-                    return Err(parse_result.unwrap_err());
-                }
-
-                Some(parse_result.unwrap())
-            },
+            Some(parse_result) => Some(parse_result?),
         };
 
         Ok(OnceSessionMapped {
             flash,
+            queued,
             errors
         })
     }
@@ -71,7 +144,7 @@ impl FromRequest for OnceSession {
     fn from_request(req: &HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
         let once_session = req.extensions()
             .get::<OnceSession>()
-            .map(|session| session.clone())
+            .cloned()
             .unwrap_or_default();
 
         return std::future::ready(Ok(once_session));
@@ -92,18 +165,35 @@ pub trait OnceSessionExt {
 
     fn flush_flash(&self) -> OnceSession;
     fn current_url(&self, url: &ServiceRequest) -> Result<(), SessionInsertError>;
+
+    fn regenerate(&self);
+    fn invalidate(&self);
+
+    fn insert_intended(&self, url: &str) -> Result<(), SessionInsertError>;
+    fn redirect_intended(&self, default: &str) -> Redirect;
+
+    fn keep_unread(&self, key: &str, value: &str) -> Result<bool, SessionInsertError>;
 }
 
-const FLASH_KEY: &'static str = "_flash";
-const ERRORS_KEY: &'static str = "_errors";
-const PREV_REQ_KEY: &'static str = "_prev_req_url";
-const CURR_REQ_KEY: &'static str = "_curr_req_url";
+pub const FLASH_KEY: &str = "_flash";
+/// Holds the flash messages `StatefulSessions::enqueue_flash` has handed over to a request.
+pub const FLASH_QUEUE_KEY: &str = "_flash_queue";
+/// Asks the store to move the session to a new key on its next update, keeping it otherwise as
+/// it is (see `StatefulSessions`).
+pub const REGENERATE_KEY: &str = "_regenerate";
+const ERRORS_KEY: &str = "_errors";
+const PREV_REQ_KEY: &str = "_prev_req_url";
+const CURR_REQ_KEY: &str = "_curr_req_url";
+const INTENDED_KEY: &str = "_intended_url";
+const KEPT_KEY: &str = "_once_kept";
 
 impl OnceSessionExt for Session {
     fn insert_flash<T>(&self, content: T) -> Result<(), SessionInsertError>
     where T : Serialize
     {
         self.insert(FLASH_KEY, content)?;
+        METRICS.flash_set.inc();
+        tracing::info!(event = "flash.set");
         return Ok(());
     }
 
@@ -119,36 +209,109 @@ impl OnceSessionExt for Session {
     fn forward_once_session<F, E>(&self, once_session: OnceSession) -> Result<(), SessionInsertError>
     where F: DeserializeOwned + Serialize, E: DeserializeOwned + Serialize
     {
-        self.insert(FLASH_KEY, once_session.flash.map(|f| serde_json::from_str::<F>(&f).unwrap()))?;
-        self.insert(ERRORS_KEY, once_session.errors.map(|e| serde_json::from_str::<E>(&e).unwrap()))?;
+        self.insert(FLASH_KEY, once_session.flash().map(|f| serde_json::from_str::<F>(f).unwrap()))?;
+        if let Some(queued) = once_session.queued() {
+            self.insert(FLASH_QUEUE_KEY, serde_json::from_str::<Vec<F>>(queued).unwrap())?;
+        }
+        self.insert(ERRORS_KEY, once_session.errors().map(|e| serde_json::from_str::<E>(e).unwrap()))?;
         return Ok(());
     }
 
+    /// Only tracked in sessions that are already stored (they always hold a version), so that
+    /// requests without a session (assets, anonymous page views) don't create one.
     fn current_url(&self, req: &ServiceRequest) -> Result<(), SessionInsertError> {
+        if self.get::<u64>(VERSION_KEY).unwrap_or(None).is_none() {
+            return Ok(());
+        }
+
         let prev_url = self
             .get::<String>(CURR_REQ_KEY)
             .unwrap_or(None)
             .unwrap_or("/".to_string());
 
         self.insert(PREV_REQ_KEY, prev_url)?;
-        self.insert(CURR_REQ_KEY, req.uri().to_string())?;
+
+        // Background requests (fetch, htmx) aren't pages anyone should be sent back to.
+        if FlashClient::of(req.headers()).is_none() {
+            self.insert(CURR_REQ_KEY, req.uri().to_string())?;
+        }
+
         return Ok(());
     }
 
+    /// Gives the session a new key, keeping its data (e.g. at login, against session fixation).
+    /// The store moves the session to the new key on update (see `REGENERATE_KEY`), so the old
+    /// key stops working at once, and the response cookie carries the new one.
+    /// The CSRF token is dropped along with the old key, so a new one is issued.
+    fn regenerate(&self) {
+        self.remove(CSRF_TOKEN_KEY);
+        if let Err(err) = self.insert(REGENERATE_KEY, true) {
+            tracing::warn!(event = "session.insert_failed", key = REGENERATE_KEY, error = %err);
+            self.renew();
+        }
+    }
+
+    /// Destroys the session, both in the store and the response cookie.
+    fn invalidate(&self) {
+        self.purge();
+    }
+
+    /// Remembers where the user was heading before being sent away (e.g. to sign in).
+    fn insert_intended(&self, url: &str) -> Result<(), SessionInsertError> {
+        self.insert(INTENDED_KEY, url)?;
+        return Ok(());
+    }
+
+    /// Redirects to the remembered intended URL, forgetting it, or to `default` if there's none.
+    /// Only local paths are followed, so the session can't be used as an open redirect.
+    fn redirect_intended(&self, default: &str) -> Redirect {
+        let intended = self
+            .remove_as::<String>(INTENDED_KEY)
+            .and_then(Result::ok)
+            .filter(|url| url.starts_with('/') && !url.starts_with("//") && !url.contains('\\'))
+            .unwrap_or_else(|| default.to_string());
+
+        return Redirect::to(intended).using_status_code(StatusCode::SEE_OTHER);
+    }
+
+    /// Puts back a flushed value nobody has read, for the next request to get, and remembers it
+    /// has been kept once. Returns `false` if the handler has set a new one in the meantime.
+    fn keep_unread(&self, key: &str, value: &str) -> Result<bool, SessionInsertError> {
+        if self.get::<serde_json::Value>(key).ok().flatten().is_some() {
+            return Ok(false);
+        }
+
+        let value = serde_json::from_str::<serde_json::Value>(value).unwrap_or_default();
+        self.insert(key, value)?;
+
+        let mut kept = self.get::<Vec<String>>(KEPT_KEY).ok().flatten().unwrap_or_default();
+        kept.push(key.to_string());
+        self.insert(KEPT_KEY, kept)?;
+        return Ok(true);
+    }
+
     fn flush_flash(&self) -> OnceSession {
         let flash = self.remove(FLASH_KEY);
+        let queued = self.remove(FLASH_QUEUE_KEY);
         let errors = self.remove(ERRORS_KEY);
         let prev_req: String = self
             .remove(PREV_REQ_KEY)
             .as_deref()
             .map(serde_json::from_str)
-            .map_or(None, |v| v.unwrap())
+            .and_then(|v| v.unwrap())
             .unwrap_or("/".into());
+        let kept = self
+            .remove_as::<Vec<String>>(KEPT_KEY)
+            .and_then(Result::ok)
+            .unwrap_or_default();
 
         return OnceSession {
             flash,
+            queued,
             errors,
             prev_req,
+            kept,
+            reads: Rc::default(),
         };
     }
 }
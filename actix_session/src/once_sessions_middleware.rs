@@ -1,16 +1,36 @@
 use std::future::{ready, Ready};
-use actix_session::SessionExt;
+use actix_session::{Session, SessionExt};
 use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
 use actix_web::Error;
 use actix_web::HttpMessage;
 use futures_util::future::LocalBoxFuture;
-use crate::once_session::OnceSessionExt;
+use crate::metrics::METRICS;
+use crate::once_session::{OnceSession, OnceSessionExt, FLASH_KEY};
 
 // There are two steps in middleware processing.
 // 1. Middleware initialization, middleware factory gets called with
 //    next service in chain as parameter.
 // 2. Middleware's call method gets called with normal request.
-pub struct FlushOnceSessions;
+//
+// Once the handler is done, the flushed values nobody has read are logged and counted as
+// dropped or, with `keep_unread`, put back for one more request.
+#[derive(Default)]
+pub struct FlushOnceSessions {
+    keep_unread: bool,
+}
+
+impl FlushOnceSessions {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    /// Gives values left unread (e.g. by a static asset or an API request) one more request to
+    /// be read on, instead of dropping them straight away.
+    pub fn keep_unread(mut self, keep: bool) -> Self {
+        self.keep_unread = keep;
+        return self;
+    }
+}
 
 // Middleware factory is `Transform` trait
 // `S` - type of the next service
@@ -28,12 +48,13 @@ where
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
-        ready(Ok(FlushOnceSessionsMiddleware { service }))
+        ready(Ok(FlushOnceSessionsMiddleware { service, keep_unread: self.keep_unread }))
     }
 }
 
 pub struct FlushOnceSessionsMiddleware<S> {
     service: S,
+    keep_unread: bool,
 }
 
 impl<S, B> Service<ServiceRequest> for FlushOnceSessionsMiddleware<S>
@@ -51,17 +72,51 @@ where
     fn call(&self, req: ServiceRequest) -> Self::Future {
         let session = req.get_session();
         if let Err(err) = session.current_url(&req) {
-            eprintln!("Failed to update session _prev_url: {}", err);
+            tracing::warn!(event = "session.insert_failed", key = "_prev_req_url", error = %err);
         };
         
         let once_session = session.flush_flash();
-        req.extensions_mut().insert(once_session);
+        req.extensions_mut().insert(once_session.clone());
 
         let fut: <S as Service<ServiceRequest>>::Future = self.service.call(req);
+        let keep_unread = self.keep_unread;
 
         Box::pin(async move {
-            let res: ServiceResponse<B> = fut.await?;
-            return Ok(res);
+            // Whatever the handler took from the session is lost all the same when it fails.
+            let res = fut.await;
+            report(&session, &once_session, keep_unread);
+            return res;
         })
     }
 }
+
+/// Logs and counts the flash the handler has read, and the values it left unread, keeping the
+/// ones that haven't been kept yet if asked to.
+fn report(session: &Session, once_session: &OnceSession, keep_unread: bool) {
+    if once_session.has_flash() && once_session.is_flash_read() {
+        METRICS.flash_consumed.inc();
+        tracing::info!(event = "flash.consumed");
+    }
+
+    for (key, value) in once_session.unread() {
+        let kept = match keep_unread && !once_session.was_kept(key) {
+            true => session.keep_unread(key, value).unwrap_or_else(|err| {
+                tracing::warn!(event = "session.insert_failed", key, error = %err);
+                false
+            }),
+            false => false,
+        };
+
+        match kept {
+            true => tracing::info!(event = "flash.kept", key),
+            false => tracing::warn!(event = "flash.dropped", key, bytes = value.len()),
+        };
+
+        if key == FLASH_KEY {
+            match kept {
+                true => METRICS.flash_kept.inc(),
+                false => METRICS.flash_dropped.inc(),
+            };
+        }
+    }
+}
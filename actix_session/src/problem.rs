@@ -0,0 +1,120 @@
+use actix_session::SessionExt;
+use actix_web::http::header::{ACCEPT, LOCATION};
+use actix_web::http::StatusCode;
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, Responder};
+use serde::Serialize;
+use serde_json::json;
+use crate::once_session::{OnceSession, OnceSessionExt};
+
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+/// Responds to a request that didn't validate, with the given errors by field. Clients asking
+/// for JSON get them right away, as a `422 Unprocessable Entity` problem details document
+/// (RFC 7807) with an `errors` member; anyone else has them flashed, and is redirected back.
+pub struct ValidationErrors<T> {
+    errors: T,
+}
+
+impl<T: Serialize> ValidationErrors<T> {
+    pub fn new(errors: T) -> Self {
+        return ValidationErrors { errors };
+    }
+}
+
+/// Whether the client would rather get problem details than be redirected.
+fn wants_problem(req: &HttpRequest) -> bool {
+    return req.headers()
+        .get(ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/json") || accept.contains(PROBLEM_JSON));
+}
+
+impl<T: Serialize> Responder for ValidationErrors<T> {
+    type Body = actix_web::body::BoxBody;
+
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse<Self::Body> {
+        if wants_problem(req) {
+            let status = StatusCode::UNPROCESSABLE_ENTITY;
+            return HttpResponse::build(status)
+                .content_type(PROBLEM_JSON)
+                .json(json!({
+                    "type": "about:blank",
+                    "title": "The request is invalid.",
+                    "status": status.as_u16(),
+                    "instance": req.path(),
+                    "errors": self.errors,
+                }));
+        }
+
+        if let Err(err) = req.get_session().insert_errors(&self.errors) {
+            tracing::warn!(event = "session.insert_failed", key = "_errors", error = %err);
+        }
+
+        let back = req.extensions()
+            .get::<OnceSession>()
+            .map(|once_session| once_session.prev_req.clone())
+            .unwrap_or_else(|| "/".into());
+
+        return HttpResponse::SeeOther()
+            .insert_header((LOCATION, back))
+            .finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_session::storage::CookieSessionStore;
+    use actix_session::{Session, SessionMiddleware};
+    use actix_web::cookie::Key;
+    use actix_web::http::header::CONTENT_TYPE;
+    use actix_web::{test, web, App};
+    use serde_json::Value;
+    use super::*;
+
+    #[actix_web::test]
+    async fn clients_asking_for_json_get_problem_details() {
+        let app = test::init_service(
+            App::new()
+                .wrap(SessionMiddleware::new(CookieSessionStore::default(), Key::generate()))
+                .route("/greet", web::post().to(|| async {
+                    return ValidationErrors::new(json!({"name": "Your name is too short."}));
+                })),
+        ).await;
+
+        for accept in ["application/json", PROBLEM_JSON, "application/problem+json, */*;q=0.1"] {
+            let req = test::TestRequest::post().uri("/greet").insert_header((ACCEPT, accept));
+            let res = test::call_service(&app, req.to_request()).await;
+
+            assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+            assert_eq!(res.headers().get(CONTENT_TYPE).unwrap(), PROBLEM_JSON);
+            let problem: Value = test::read_body_json(res).await;
+            assert_eq!(problem["status"], 422);
+            assert_eq!(problem["instance"], "/greet");
+            assert_eq!(problem["errors"], json!({"name": "Your name is too short."}));
+        }
+    }
+
+    #[actix_web::test]
+    async fn anyone_else_has_the_errors_flashed() {
+        let app = test::init_service(
+            App::new()
+                .wrap(SessionMiddleware::new(CookieSessionStore::default(), Key::generate()))
+                .route("/greet", web::post().to(|| async {
+                    return ValidationErrors::new(json!({"name": "Your name is too short."}));
+                }))
+                .route("/state", web::get().to(|session: Session| async move {
+                    return HttpResponse::Ok().json(session.entries().clone());
+                })),
+        ).await;
+
+        let req = test::TestRequest::post().uri("/greet").insert_header((ACCEPT, "text/html"));
+        let res = test::call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+        assert_eq!(res.headers().get(LOCATION).unwrap(), "/");
+
+        let cookie = res.response().cookies().next().unwrap().into_owned();
+        let req = test::TestRequest::get().uri("/state").cookie(cookie);
+        let state: Value = test::read_body_json(test::call_service(&app, req.to_request()).await).await;
+        assert_eq!(state["_errors"], json!({"name": "Your name is too short."}).to_string());
+    }
+}
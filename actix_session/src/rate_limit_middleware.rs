@@ -0,0 +1,129 @@
+use std::cell::Cell;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::cookie::Cookie;
+use actix_web::http::header::{HeaderValue, SET_COOKIE};
+use actix_web::Error;
+use futures_util::future::LocalBoxFuture;
+use crate::rate_limit::{client_key, CurrentClient, CURRENT_CLIENT};
+
+/// Tells the session store which client each request comes from, so it can rate-limit the
+/// sessions it creates (see `StatefulSessions::limit_creation`). Requests whose session got
+/// refused keep the handler's response, sent session-less: without the session cookie.
+///
+/// Must be wrapped *after* `SessionMiddleware`, so the session is saved within it, and given the
+/// session cookie name `SessionMiddleware` has been built with (actix-session's `id` by default).
+pub struct LimitSessionCreation {
+    trust_forwarded_for: bool,
+    cookie_name: String,
+}
+
+impl Default for LimitSessionCreation {
+    fn default() -> Self {
+        return LimitSessionCreation { trust_forwarded_for: false, cookie_name: "id".into() };
+    }
+}
+
+impl LimitSessionCreation {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    /// Identifies clients by their `Forwarded`/`X-Forwarded-For` address rather than the peer
+    /// one. Only enable it behind a proxy that overwrites these headers.
+    pub fn trust_forwarded_for(mut self, trust: bool) -> Self {
+        self.trust_forwarded_for = trust;
+        return self;
+    }
+
+    pub fn cookie_name(mut self, name: impl Into<String>) -> Self {
+        self.cookie_name = name.into();
+        return self;
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for LimitSessionCreation
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = LimitSessionCreationMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(LimitSessionCreationMiddleware {
+            service,
+            trust_forwarded_for: self.trust_forwarded_for,
+            cookie_name: Rc::from(self.cookie_name.as_str()),
+        }))
+    }
+}
+
+pub struct LimitSessionCreationMiddleware<S> {
+    service: S,
+    trust_forwarded_for: bool,
+    cookie_name: Rc<str>,
+}
+
+/// Drops the `cookie_name` cookie from the response's `Set-Cookie` headers.
+fn remove_cookie<B>(res: &mut ServiceResponse<B>, cookie_name: &str) {
+    let headers = res.headers_mut();
+    let kept = headers
+        .get_all(SET_COOKIE)
+        .filter(|header| {
+            header
+                .to_str()
+                .ok()
+                .and_then(|header| Cookie::parse(header).ok())
+                .is_none_or(|cookie| cookie.name() != cookie_name)
+        })
+        .cloned()
+        .collect::<Vec<HeaderValue>>();
+
+    headers.remove(SET_COOKIE);
+    for header in kept {
+        headers.append(SET_COOKIE, header);
+    }
+}
+
+impl<S, B> Service<ServiceRequest> for LimitSessionCreationMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let client = CurrentClient {
+            key: client_key(&req, self.trust_forwarded_for),
+            limited: Cell::new(false),
+        };
+
+        let fut: <S as Service<ServiceRequest>>::Future = self.service.call(req);
+        let cookie_name = self.cookie_name.clone();
+
+        Box::pin(CURRENT_CLIENT.scope(client, async move {
+            let mut res = fut.await?;
+
+            if CURRENT_CLIENT.with(|client| client.limited.get()) {
+                tracing::warn!(
+                    event = "session.rate_limited",
+                    client = CURRENT_CLIENT.with(|client| client.key.clone()),
+                );
+                remove_cookie(&mut res, &cookie_name);
+            }
+
+            return Ok(res);
+        }))
+    }
+}
@@ -0,0 +1,130 @@
+use actix_session::config::{CookieContentSecurity, PersistentSession};
+use actix_session::storage::SessionStore;
+use actix_session::SessionMiddleware;
+use actix_web::cookie::time::Duration;
+use actix_web::cookie::{Cookie, Key, SameSite};
+
+/// The session cookie settings, built into `SessionMiddleware` by [`SessionCookie::middleware`]
+/// and handed to the middlewares that write the same cookie (`RotateSessionKeys`), so they all
+/// agree on it. The defaults mirror actix-session's.
+#[derive(Clone)]
+pub struct SessionCookie {
+    name: String,
+    content_security: CookieContentSecurity,
+    path: String,
+    domain: Option<String>,
+    secure: bool,
+    http_only: bool,
+    same_site: SameSite,
+    max_age: Option<Duration>,
+}
+
+impl Default for SessionCookie {
+    fn default() -> Self {
+        return SessionCookie {
+            name: "id".into(),
+            content_security: CookieContentSecurity::Private,
+            path: "/".into(),
+            domain: None,
+            secure: true,
+            http_only: true,
+            same_site: SameSite::Lax,
+            max_age: None,
+        };
+    }
+}
+
+impl SessionCookie {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        return self;
+    }
+
+    pub fn content_security(mut self, content_security: CookieContentSecurity) -> Self {
+        self.content_security = content_security;
+        return self;
+    }
+
+    pub fn path(mut self, path: impl Into<String>) -> Self {
+        self.path = path.into();
+        return self;
+    }
+
+    pub fn domain(mut self, domain: impl Into<String>) -> Self {
+        self.domain = Some(domain.into());
+        return self;
+    }
+
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        return self;
+    }
+
+    pub fn http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        return self;
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = same_site;
+        return self;
+    }
+
+    /// Makes the cookie outlive the browser session, for `max_age` (a `PersistentSession` with
+    /// that TTL). Without it, the cookie is dropped when the browser closes.
+    pub fn persistent(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        return self;
+    }
+
+    pub fn cookie_name(&self) -> &str {
+        return &self.name;
+    }
+
+    pub fn cookie_content_security(&self) -> CookieContentSecurity {
+        return self.content_security;
+    }
+
+    /// Builds a `SessionMiddleware` for `store` that issues this cookie, signed (or encrypted)
+    /// with `key`.
+    pub fn middleware<S: SessionStore>(&self, store: S, key: Key) -> SessionMiddleware<S> {
+        let mut builder = SessionMiddleware::builder(store, key)
+            .cookie_name(self.name.clone())
+            .cookie_content_security(self.content_security)
+            .cookie_path(self.path.clone())
+            .cookie_domain(self.domain.clone())
+            .cookie_secure(self.secure)
+            .cookie_http_only(self.http_only)
+            .cookie_same_site(self.same_site);
+
+        if let Some(max_age) = self.max_age {
+            builder = builder.session_lifecycle(PersistentSession::default().session_ttl(max_age));
+        }
+
+        return builder.build();
+    }
+
+    /// Returns a cookie holding `value` (not yet signed) with the attributes `SessionMiddleware`
+    /// sets on its own session cookie.
+    pub fn build(&self, value: String) -> Cookie<'static> {
+        let mut cookie = Cookie::new(self.name.clone(), value);
+        cookie.set_path(self.path.clone());
+        cookie.set_secure(self.secure);
+        cookie.set_http_only(self.http_only);
+        cookie.set_same_site(self.same_site);
+
+        if let Some(max_age) = self.max_age {
+            cookie.set_max_age(max_age);
+        }
+
+        if let Some(domain) = &self.domain {
+            cookie.set_domain(domain.clone());
+        }
+
+        return cookie;
+    }
+}
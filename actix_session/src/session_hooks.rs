@@ -0,0 +1,46 @@
+use crate::session_hash::HashedKey;
+
+/// Why a hook is being notified about a session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionEvent {
+    /// A new session has been stored.
+    Created,
+    /// The session has been given a new key, keeping its data.
+    Renewed,
+    /// The session outlived its TTL and has been dropped.
+    Expired,
+    /// The session has been explicitly deleted.
+    Destroyed,
+    /// The session has been dropped to make room for others.
+    Evicted,
+}
+
+impl SessionEvent {
+    /// The name the event is logged under, e.g. `session.created`.
+    pub fn name(&self) -> &'static str {
+        return match self {
+            SessionEvent::Created => "session.created",
+            SessionEvent::Renewed => "session.renewed",
+            SessionEvent::Expired => "session.expired",
+            SessionEvent::Destroyed => "session.destroyed",
+            SessionEvent::Evicted => "session.evicted",
+        };
+    }
+}
+
+/// Observer of sessions lifecycle, e.g. for audit logging, cleaning data related to a session
+/// or collecting metrics.
+///
+/// Hooks are only given the hashed session key, and are called once the store has released its
+/// lock, so they may call back into the store.
+pub trait SessionHook: Send + Sync {
+    fn on_event(&self, hashed_key: &HashedKey, event: SessionEvent);
+}
+
+impl<F> SessionHook for F
+where F: Fn(&HashedKey, SessionEvent) + Send + Sync
+{
+    fn on_event(&self, hashed_key: &HashedKey, event: SessionEvent) {
+        self(hashed_key, event);
+    }
+}
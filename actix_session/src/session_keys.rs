@@ -0,0 +1,84 @@
+use std::env;
+use std::fs;
+
+use actix_web::cookie::Key;
+
+/// Minimum length of a secret accepted by [`Key::derive_from`].
+const MIN_SECRET_LEN: usize = 32;
+
+/// One active key, used to sign (and encrypt) new cookies, plus a list of retired keys that are
+/// still accepted when verifying cookies issued before the last rotation.
+#[derive(Clone)]
+pub struct SessionKeys {
+    active: Key,
+    retired: Vec<Key>,
+}
+
+impl SessionKeys {
+    pub fn new(active: Key, retired: Vec<Key>) -> Self {
+        return SessionKeys { active, retired };
+    }
+
+    /// Generates a random, ephemeral key. Every cookie signed with it becomes invalid once the
+    /// server restarts.
+    pub fn generate() -> Self {
+        return SessionKeys::new(Key::generate(), Vec::new());
+    }
+
+    /// Loads the keys from the environment, using `var` as a prefix:
+    ///
+    /// - `{var}` or `{var}_FILE`: the active secret, or a path to a file holding it;
+    /// - `{var}_RETIRED` or `{var}_RETIRED_FILE`: comma (or line) separated retired secrets, or a
+    ///   path to a file holding one secret per line.
+    ///
+    /// Every secret must be at least 32 bytes long. Returns `Ok(None)` if no active secret has
+    /// been configured at all.
+    pub fn from_env(var: &str) -> Result<Option<Self>, anyhow::Error> {
+        let active = match read_var(var)? {
+            None => return Ok(None),
+            Some(secret) => derive_key(secret.trim())?,
+        };
+
+        let retired = read_var(&format!("{var}_RETIRED"))?
+            .unwrap_or_default()
+            .split([',', '\n'])
+            .map(str::trim)
+            .filter(|secret| !secret.is_empty())
+            .map(derive_key)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        return Ok(Some(SessionKeys::new(active, retired)));
+    }
+
+    pub fn active(&self) -> &Key {
+        return &self.active;
+    }
+
+    pub fn retired(&self) -> &[Key] {
+        return &self.retired;
+    }
+}
+
+/// Reads `var` from the environment or, if it isn't set, the file pointed by `{var}_FILE`.
+fn read_var(var: &str) -> Result<Option<String>, anyhow::Error> {
+    if let Ok(value) = env::var(var) {
+        return Ok(Some(value));
+    }
+
+    return match env::var(format!("{var}_FILE")) {
+        Err(_) => Ok(None),
+        Ok(path) => fs::read_to_string(&path)
+            .map(Some)
+            .map_err(|err| anyhow::anyhow!("Failed to read {var}_FILE ({path}): {err}")),
+    };
+}
+
+fn derive_key(secret: &str) -> Result<Key, anyhow::Error> {
+    if secret.len() < MIN_SECRET_LEN {
+        return Err(anyhow::anyhow!(
+            "Session secrets must be at least {MIN_SECRET_LEN} bytes long."
+        ));
+    }
+
+    return Ok(Key::derive_from(secret.as_bytes()));
+}
@@ -1,35 +1,746 @@
-use std::collections::HashMap;
-use std::sync::{Arc, LazyLock, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::collections::{HashMap, VecDeque};
+use std::str::FromStr;
+use std::sync::{Arc, LazyLock, Mutex, RwLock, RwLockWriteGuard};
 use actix_session::storage::{generate_session_key, LoadError, SaveError, SessionKey, SessionStore, UpdateError};
-use actix_web::cookie::time::Duration;
+use actix_web::cookie::time::{Duration, OffsetDateTime};
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use crate::metrics::METRICS;
+use crate::once_session::{FLASH_QUEUE_KEY, REGENERATE_KEY};
+use crate::identity::{IDENTITY_KEYS, USER_AGENT_KEY, USER_KEY};
+use crate::rate_limit::{acquire_for_current_client, SessionRateLimiter};
+use crate::session_hash::HashedKey;
+use crate::session_hooks::{SessionEvent, SessionHook};
+use crate::trace_middleware::record_session;
 
 pub(crate) type SessionState = HashMap<String, String>;
 
+/// Reserved entry holding the version a session state has been loaded at, so `update` can tell
+/// whether the session has been written to since.
+pub const VERSION_KEY: &str = "_session_version";
+
+/// Reserved entry holding how far into the session's queue of enqueued flash messages a state has
+/// been loaded, so `update` can drop the ones it has delivered.
+pub const QUEUED_UP_TO_KEY: &str = "_flash_queued_up_to";
+
+/// How many past states are kept per session to merge stale updates against.
+const HISTORY_LEN: usize = 8;
+
+/// How `update` resolves a session that has been written to since it was loaded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// The update replaces the whole stored state anyway.
+    LastWriterWins,
+    /// Only the keys the update has changed since it was loaded are applied over the stored
+    /// state, so values written in the meantime by someone else are kept.
+    #[default]
+    MergeKeys,
+    /// The update fails.
+    Reject,
+}
+
+/// Parses a policy by its kebab-case name, e.g. `merge-keys`.
+impl FromStr for ConflictPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        return match policy {
+            "last-writer-wins" => Ok(ConflictPolicy::LastWriterWins),
+            "merge-keys" => Ok(ConflictPolicy::MergeKeys),
+            "reject" => Ok(ConflictPolicy::Reject),
+            _ => Err(anyhow::anyhow!("Unknown conflict policy `{}`.", policy)),
+        };
+    }
+}
+
 struct Session {
     session: SessionState,
     ttl: Duration,
-} 
+    created_at: OffsetDateTime,
+    last_seen: OffsetDateTime,
+    /// When the session has been given its current key.
+    key_issued_at: OffsetDateTime,
+    version: u64,
+    /// Past states, oldest first, along with their versions.
+    history: VecDeque<(u64, SessionState)>,
+    /// The signed in user, and the user agent they signed in from, read from the state.
+    user_id: Option<String>,
+    user_agent: Option<String>,
+    /// Flash messages enqueued from outside a request, handed over by every `load` until an
+    /// update made from it commits.
+    queued: Vec<Value>,
+    /// How many enqueued messages have been delivered, and dropped from `queued`, so far.
+    dequeued: u64,
+}
+
+/// Reads a string entry of a session state, whose values are JSON.
+fn string_entry(state: &SessionState, key: &str) -> Option<String> {
+    return state
+        .get(key)
+        .and_then(|value| serde_json::from_str::<String>(value).ok());
+}
+
+impl Session {
+    fn new(session: SessionState, ttl: Duration) -> Self {
+        let now = OffsetDateTime::now_utc();
+        return Session {
+            user_id: string_entry(&session, USER_KEY),
+            user_agent: string_entry(&session, USER_AGENT_KEY),
+            session,
+            ttl,
+            created_at: now,
+            last_seen: now,
+            key_issued_at: now,
+            version: 0,
+            history: VecDeque::new(),
+            queued: Vec::new(),
+            dequeued: 0,
+        };
+    }
+
+    fn touch(&mut self) {
+        self.last_seen = OffsetDateTime::now_utc();
+    }
+
+    fn write(&mut self, state: SessionState, keep_history: bool) {
+        self.user_id = string_entry(&state, USER_KEY);
+        self.user_agent = string_entry(&state, USER_AGENT_KEY);
+
+        let previous = std::mem::replace(&mut self.session, state);
+        if keep_history {
+            self.history.push_back((self.version, previous));
+            if self.history.len() > HISTORY_LEN {
+                self.history.pop_front();
+            }
+        }
+
+        self.version += 1;
+        self.touch();
+    }
+
+    /// Drops the enqueued messages a state loaded `up_to` there has delivered. Those enqueued
+    /// since, or already dropped by another update, are left alone.
+    fn dequeue(&mut self, up_to: u64) {
+        let delivered = (up_to.saturating_sub(self.dequeued) as usize).min(self.queued.len());
+        self.queued.drain(..delivered);
+        self.dequeued += delivered as u64;
+    }
+
+    fn state_at(&self, version: u64) -> Option<&SessionState> {
+        return self.history
+            .iter()
+            .find(|(past_version, _)| *past_version == version)
+            .map(|(_, state)| state);
+    }
+}
+
+/// Applies the changes `ours` made to `base` over `theirs`, key by key. Keys changed on both
+/// sides take `ours` value.
+fn merge(base: &SessionState, ours: SessionState, theirs: &SessionState) -> SessionState {
+    let mut merged = theirs.clone();
+    for key in base.keys() {
+        if !ours.contains_key(key) {
+            merged.remove(key);
+        }
+    }
+
+    for (key, value) in ours {
+        if base.get(&key) != Some(&value) {
+            merged.insert(key, value);
+        }
+    }
+
+    return merged;
+}
+
+/// The key a session is rotated to. It is derived from the current key (and the secret keys are
+/// hashed with), so requests still carrying the old key during the grace window can be told
+/// the new one without the store ever holding a raw key.
+fn rotated_key(session_key: &str) -> String {
+    return HashedKey::new(&format!("rotation:{session_key}")).to_string();
+}
+
+#[derive(Clone, Copy)]
+struct KeyRotation {
+    interval: Duration,
+    grace: Duration,
+}
+
+#[derive(Default)]
+struct SessionsMap {
+    sessions: HashMap<HashedKey, Session>,
+    /// Rotated keys, along with the end of their grace window.
+    rotated: HashMap<HashedKey, OffsetDateTime>,
+    /// The sessions of every signed in user.
+    users: HashMap<String, Vec<HashedKey>>,
+}
+
+impl SessionsMap {
+    fn insert(&mut self, hashed_key: HashedKey, session: Session) {
+        if let Some(user_id) = &session.user_id {
+            self.users.entry(user_id.clone()).or_default().push(hashed_key.clone());
+        }
+
+        self.sessions.insert(hashed_key, session);
+    }
+
+    fn remove(&mut self, hashed_key: &HashedKey) -> Option<Session> {
+        let session = self.sessions.remove(hashed_key)?;
+        if let Some(user_id) = &session.user_id {
+            self.unindex(user_id, hashed_key);
+        }
+
+        return Some(session);
+    }
+
+    fn unindex(&mut self, user_id: &str, hashed_key: &HashedKey) {
+        if let Some(keys) = self.users.get_mut(user_id) {
+            keys.retain(|key| key != hashed_key);
+            if keys.is_empty() {
+                self.users.remove(user_id);
+            }
+        }
+    }
+
+    /// Moves a session written to in place to its current user's index.
+    fn reindex(&mut self, hashed_key: &HashedKey, previous_user: Option<String>) {
+        let user_id = self.sessions
+            .get(hashed_key)
+            .and_then(|session| session.user_id.clone());
+
+        if user_id == previous_user {
+            return;
+        }
+
+        if let Some(previous_user) = previous_user {
+            self.unindex(&previous_user, hashed_key);
+        }
+
+        if let Some(user_id) = user_id {
+            self.users.entry(user_id).or_default().push(hashed_key.clone());
+        }
+    }
+
+    /// Removes the oldest sessions of the user `hashed_key` belongs to past `cap`, never
+    /// `hashed_key` itself. Returns the removed ones.
+    fn cap_user(&mut self, hashed_key: &HashedKey, cap: usize) -> Vec<HashedKey> {
+        let Some(user_id) = self.sessions.get(hashed_key).and_then(|s| s.user_id.clone()) else {
+            return Vec::new();
+        };
+
+        let keys = self.users.get(&user_id).cloned().unwrap_or_default();
+        if keys.len() <= cap {
+            return Vec::new();
+        }
+
+        let mut others = keys
+            .iter()
+            .filter(|key| *key != hashed_key)
+            .filter_map(|key| Some((self.sessions.get(key)?.created_at, key.clone())))
+            .collect::<Vec<_>>();
+        others.sort_by_key(|(created_at, _)| *created_at);
+
+        let evicted = others
+            .into_iter()
+            .take(keys.len() - cap)
+            .map(|(_, key)| key)
+            .collect::<Vec<_>>();
+
+        for key in &evicted {
+            self.remove(key);
+        }
+
+        return evicted;
+    }
+
+    /// Follows a key through the rotations its session went through, as long as their grace
+    /// windows are still open. Returns the current key, and its hash.
+    fn follow(&mut self, session_key: &str, now: OffsetDateTime) -> (String, HashedKey) {
+        let mut session_key = session_key.to_string();
+        let mut hashed_key = HashedKey::new(&session_key);
 
-static SESSIONS: LazyLock<Arc<RwLock<HashMap<Box<str>, Session>>>> = LazyLock::new(|| {
-    Arc::new(RwLock::new(HashMap::new()))
+        while let Some(grace_end) = self.rotated.get(&hashed_key) {
+            if *grace_end <= now {
+                self.rotated.remove(&hashed_key);
+                break;
+            }
+
+            session_key = rotated_key(&session_key);
+            hashed_key = HashedKey::new(&session_key);
+        }
+
+        return (session_key, hashed_key);
+    }
+
+    /// Moves the session to its rotated key, keeping the current one valid for `grace`.
+    fn rotate(
+        &mut self,
+        session_key: &str,
+        hashed_key: &HashedKey,
+        grace: Duration,
+        now: OffsetDateTime,
+    ) -> Option<(String, HashedKey)> {
+        let mut session = self.remove(hashed_key)?;
+        session.key_issued_at = now;
+
+        let new_key = rotated_key(session_key);
+        let new_hashed_key = HashedKey::new(&new_key);
+        self.insert(new_hashed_key.clone(), session);
+
+        self.rotated.retain(|_, grace_end| *grace_end > now);
+        self.rotated.insert(hashed_key.clone(), now + grace);
+
+        return Some((new_key, new_hashed_key));
+    }
+
+    /// Moves the session to a new random key. Unlike a rotation, the current key stops working
+    /// at once.
+    fn rekey(&mut self, hashed_key: &HashedKey, now: OffsetDateTime) -> Option<(String, HashedKey)> {
+        let mut session = self.remove(hashed_key)?;
+        session.key_issued_at = now;
+
+        let new_key = generate_session_key().as_ref().to_string();
+        let new_hashed_key = HashedKey::new(&new_key);
+        self.insert(new_hashed_key.clone(), session);
+
+        return Some((new_key, new_hashed_key));
+    }
+}
+
+/// Hands the watchers of a session over to the key it has moved to.
+fn move_watchers(hashed_key: &HashedKey, new_hashed_key: &HashedKey) {
+    let mut watchers = WATCHERS.lock().unwrap();
+    if let Some(senders) = watchers.remove(hashed_key) {
+        watchers.insert(new_hashed_key.clone(), senders);
+    }
+}
+
+static SESSIONS: LazyLock<Arc<RwLock<SessionsMap>>> = LazyLock::new(|| {
+    Arc::new(RwLock::new(SessionsMap::default()))
 });
 
-fn write_sessions<'a>() -> RwLockWriteGuard<'a, HashMap<Box<str>, Session>> {
+/// How many pushed messages a watcher may lag behind before it's dropped.
+const WATCHER_CAPACITY: usize = 16;
+
+/// The open subscriptions to the flash messages pushed to each session.
+static WATCHERS: LazyLock<Mutex<HashMap<HashedKey, Vec<Sender<Value>>>>> =
+    LazyLock::new(Mutex::default);
+
+fn write_sessions<'a>() -> RwLockWriteGuard<'a, SessionsMap> {
     return SESSIONS.write().unwrap_or_else(|mut e| {
-        **e.get_mut() = HashMap::new();
+        **e.get_mut() = SessionsMap::default();
         SESSIONS.clear_poison();
         e.into_inner()
     });
 }
 
-fn read_sessions<'a>() -> RwLockReadGuard<'a, HashMap<Box<str>, Session>> {
-    if SESSIONS.is_poisoned() {
-        drop(write_sessions());
-    }
-    return SESSIONS.read().unwrap();
+/// A signed in user's session, as listed by `StatefulSessions::user_sessions`.
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    pub hashed_key: HashedKey,
+    pub created_at: OffsetDateTime,
+    pub last_seen: OffsetDateTime,
+    pub user_agent: Option<String>,
+}
+
+/// A stored session, as listed by `StatefulSessions::list`.
+#[derive(Debug, Clone)]
+pub struct SessionSummary {
+    pub hashed_key: HashedKey,
+    /// Approximate size of the state, in bytes.
+    pub bytes: usize,
+    pub keys: Vec<String>,
+    pub created_at: OffsetDateTime,
+    /// How long until the session expires, unless it's used again.
+    pub ttl: Duration,
+    pub user_id: Option<String>,
 }
 
-pub struct StatefulSessions;
+/// An in-memory `SessionStore`. Sessions expire after `idle_timeout` without activity (falling back
+/// to the TTL given by `SessionMiddleware`), and once they've lived for `absolute_lifetime`, no
+/// matter how active they are. Both are enforced on `load`.
+///
+/// Every stored session carries a version, handed over to the loaded state under `VERSION_KEY`.
+/// Updates made from a stale version are resolved according to the `ConflictPolicy`.
+///
+/// Sessions are indexed by the keyed hash of their key, never by the key itself. With key
+/// rotation enabled, `update` gives active sessions a new key every `interval`. The old key keeps
+/// working for `grace`, so requests already in flight with it don't lose their session. A state
+/// holding `REGENERATE_KEY` (see `OnceSessionExt::regenerate`) is moved to a new key by `update`
+/// right away, with no grace: it keeps its creation time, hooks see it renewed, and it doesn't
+/// count against the creation limiter.
+///
+/// Registered hooks are notified of every session lifecycle event.
+///
+/// Sessions holding a signed in user (see `Identity`) are indexed by user, so they can be listed
+/// and revoked together, and capped: past `max_sessions_per_user`, the oldest ones are evicted.
+///
+/// With a creation limiter set, `save` refuses new sessions to clients over the limit: nothing is
+/// stored, and `LimitSessionCreation` (which must wrap `SessionMiddleware`, and tells clients
+/// apart) sends the response without a session cookie.
+///
+/// Sessions can be watched for flash messages pushed to them (e.g. by a background task), which
+/// are handed over to their watchers straight away. Watching ends along with the session, and
+/// follows it through key rotations.
+#[derive(Clone, Default)]
+pub struct StatefulSessions {
+    idle_timeout: Option<Duration>,
+    absolute_lifetime: Option<Duration>,
+    conflict_policy: ConflictPolicy,
+    key_rotation: Option<KeyRotation>,
+    hooks: Vec<Arc<dyn SessionHook>>,
+    creation_limiter: Option<Arc<dyn SessionRateLimiter>>,
+    max_sessions_per_user: Option<usize>,
+}
+
+impl StatefulSessions {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    /// Sliding expiry: extended every time the session is loaded or written.
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = Some(idle_timeout);
+        return self;
+    }
+
+    /// Hard cap, measured from the session creation. Activity never extends it.
+    pub fn absolute_lifetime(mut self, absolute_lifetime: Duration) -> Self {
+        self.absolute_lifetime = Some(absolute_lifetime);
+        return self;
+    }
+
+    pub fn conflict_policy(mut self, conflict_policy: ConflictPolicy) -> Self {
+        self.conflict_policy = conflict_policy;
+        return self;
+    }
+
+    pub fn rotate_keys(mut self, interval: Duration, grace: Duration) -> Self {
+        self.key_rotation = Some(KeyRotation { interval, grace });
+        return self;
+    }
+
+    pub fn hook(mut self, hook: impl SessionHook + 'static) -> Self {
+        self.hooks.push(Arc::new(hook));
+        return self;
+    }
+
+    /// The limiter must be shared by every worker's store for the limit to hold across them.
+    pub fn limit_creation(mut self, limiter: Arc<dyn SessionRateLimiter>) -> Self {
+        self.creation_limiter = Some(limiter);
+        return self;
+    }
+
+    pub fn max_sessions_per_user(mut self, max_sessions: usize) -> Self {
+        self.max_sessions_per_user = Some(max_sessions.max(1));
+        return self;
+    }
+
+    /// Every live session of `user_id`, oldest first.
+    pub fn user_sessions(&self, user_id: &str) -> Vec<SessionInfo> {
+        let now = OffsetDateTime::now_utc();
+        let mut sessions = write_sessions();
+        let keys = sessions.users.get(user_id).cloned().unwrap_or_default();
+
+        let mut expired = Vec::new();
+        let mut listed = Vec::new();
+        for key in keys {
+            let Some(session) = sessions.sessions.get(&key) else {
+                continue;
+            };
+
+            if self.is_expired(session, now) {
+                expired.push(key);
+                continue;
+            }
+
+            listed.push(SessionInfo {
+                created_at: session.created_at,
+                last_seen: session.last_seen,
+                user_agent: session.user_agent.clone(),
+                hashed_key: key,
+            });
+        }
+
+        for key in &expired {
+            sessions.remove(key);
+        }
+        drop(sessions);
+
+        for key in &expired {
+            self.emit(key, SessionEvent::Expired);
+        }
+
+        listed.sort_by_key(|session| session.created_at);
+        return listed;
+    }
+
+    /// Revokes one of the sessions of `user_id`. Returns `false` if it isn't one of theirs.
+    pub fn revoke_user_session(&self, user_id: &str, hashed_key: &HashedKey) -> bool {
+        let mut sessions = write_sessions();
+        let owned = sessions.sessions
+            .get(hashed_key)
+            .is_some_and(|session| session.user_id.as_deref() == Some(user_id));
+
+        if !owned {
+            return false;
+        }
+
+        sessions.remove(hashed_key);
+        drop(sessions);
+
+        self.emit(hashed_key, SessionEvent::Destroyed);
+        return true;
+    }
+
+    /// Revokes every session of `user_id` ("log out everywhere"). Returns how many there were.
+    pub fn revoke_user_sessions(&self, user_id: &str) -> usize {
+        let mut sessions = write_sessions();
+        let keys = sessions.users.get(user_id).cloned().unwrap_or_default();
+        for key in &keys {
+            sessions.remove(key);
+        }
+        drop(sessions);
+
+        for key in &keys {
+            self.emit(key, SessionEvent::Destroyed);
+        }
+
+        return keys.len();
+    }
+
+    /// Every live session, oldest first.
+    pub fn list(&self) -> Vec<SessionSummary> {
+        let now = OffsetDateTime::now_utc();
+        let sessions = SESSIONS.read().unwrap_or_else(|e| e.into_inner());
+
+        let mut listed = sessions.sessions
+            .iter()
+            .filter(|(_, session)| !self.is_expired(session, now))
+            .map(|(hashed_key, session)| {
+                let mut keys = session.session.keys().cloned().collect::<Vec<_>>();
+                keys.sort();
+
+                SessionSummary {
+                    hashed_key: hashed_key.clone(),
+                    bytes: session.session
+                        .iter()
+                        .map(|(key, value)| key.len() + value.len())
+                        .sum(),
+                    keys,
+                    created_at: session.created_at,
+                    ttl: self.expires_at(session) - now,
+                    user_id: session.user_id.clone(),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        listed.sort_by_key(|session| session.created_at);
+        return listed;
+    }
+
+    /// How many sessions are stored, and their approximate size in bytes.
+    pub fn stored(&self) -> (usize, usize) {
+        let sessions = SESSIONS.read().unwrap_or_else(|e| e.into_inner());
+        let bytes = sessions.sessions
+            .values()
+            .flat_map(|session| session.session.iter())
+            .map(|(key, value)| key.len() + value.len())
+            .sum();
+
+        return (sessions.sessions.len(), bytes);
+    }
+
+    /// The state of a live session.
+    pub fn inspect(&self, hashed_key: &HashedKey) -> Option<SessionState> {
+        let now = OffsetDateTime::now_utc();
+        let sessions = SESSIONS.read().unwrap_or_else(|e| e.into_inner());
+
+        return sessions.sessions
+            .get(hashed_key)
+            .filter(|session| !self.is_expired(session, now))
+            .map(|session| session.session.clone());
+    }
+
+    /// Destroys a session by its hashed key. Returns `false` if it isn't stored.
+    pub fn revoke(&self, hashed_key: &HashedKey) -> bool {
+        let removed = write_sessions().remove(hashed_key);
+        if removed.is_some() {
+            self.emit(hashed_key, SessionEvent::Destroyed);
+        }
+
+        return removed.is_some();
+    }
+
+    /// Destroys every stored session. Returns how many there were.
+    pub fn revoke_all(&self) -> usize {
+        let mut sessions = write_sessions();
+        let keys = sessions.sessions.keys().cloned().collect::<Vec<_>>();
+        for key in &keys {
+            sessions.remove(key);
+        }
+        drop(sessions);
+
+        for key in &keys {
+            self.emit(key, SessionEvent::Destroyed);
+        }
+
+        return keys.len();
+    }
+
+    /// Subscribes to the flash messages pushed to a session, as `{"flash": ..}` payloads. The
+    /// receiver is closed once the session is gone, or once it lags `WATCHER_CAPACITY` messages
+    /// behind.
+    pub fn watch(&self, hashed_key: &HashedKey) -> Receiver<Value> {
+        let (sender, receiver) = channel(WATCHER_CAPACITY);
+        let mut watchers = WATCHERS.lock().unwrap();
+        let senders = watchers.entry(hashed_key.clone()).or_default();
+        // Drops the watchers that have gone away (e.g. pages that reconnected since), which a
+        // session nobody pushes to would otherwise keep forever.
+        senders.retain(|sender| !sender.is_closed());
+        senders.push(sender);
+        return receiver;
+    }
+
+    /// Pushes a flash message to the watchers of a session. Returns how many got it.
+    pub fn push_flash(&self, hashed_key: &HashedKey, message: impl Serialize) -> usize {
+        let payload = json!({"flash": message});
+        let mut watchers = WATCHERS.lock().unwrap();
+        let Some(senders) = watchers.get_mut(hashed_key) else {
+            return 0;
+        };
+
+        // Watchers too slow to keep up are dropped, which ends their stream.
+        senders.retain(|sender| match sender.try_send(payload.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                tracing::warn!(event = "flash.watcher_dropped", session = %hashed_key);
+                false
+            },
+            Err(TrySendError::Closed(_)) => false,
+        });
+        let delivered = senders.len();
+        if senders.is_empty() {
+            watchers.remove(hashed_key);
+        }
+
+        tracing::info!(event = "flash.pushed", session = %hashed_key, delivered);
+        return delivered;
+    }
+
+    /// Pushes a flash message to the watchers of every session of a user. Returns how many got
+    /// it.
+    pub fn push_flash_to_user(&self, user_id: &str, message: impl Serialize) -> usize {
+        let message = json!(message);
+        return self
+            .user_sessions(user_id)
+            .iter()
+            .map(|session| self.push_flash(&session.hashed_key, &message))
+            .sum();
+    }
+
+    /// Enqueues a flash message for the next request of a session, e.g. from a background job.
+    /// Unlike `insert_flash`, it doesn't need the session's request, and unlike `push_flash`, it
+    /// doesn't need anyone watching. Enqueueing doesn't keep the session alive. Returns `false`
+    /// if it isn't stored.
+    pub fn enqueue_flash(&self, hashed_key: &HashedKey, message: impl Serialize) -> bool {
+        let Ok(message) = serde_json::to_value(message) else {
+            return false;
+        };
+
+        let now = OffsetDateTime::now_utc();
+        let mut sessions = write_sessions();
+        let Some(session) = sessions.sessions.get_mut(hashed_key) else {
+            return false;
+        };
+
+        if self.is_expired(session, now) {
+            return false;
+        }
+
+        // Kept apart from the state until it's loaded, so that it can't get lost merging the
+        // update of a request in flight.
+        session.queued.push(message);
+        tracing::info!(event = "flash.enqueued", session = %hashed_key, queued = session.queued.len());
+        return true;
+    }
+
+    /// Enqueues a flash message for the next request of every session of a user. Returns how
+    /// many sessions it was enqueued for.
+    pub fn enqueue_flash_to_user(&self, user_id: &str, message: impl Serialize) -> usize {
+        let message = json!(message);
+        return self
+            .user_sessions(user_id)
+            .iter()
+            .filter(|session| self.enqueue_flash(&session.hashed_key, &message))
+            .count();
+    }
+
+    /// Evicts the user's oldest sessions once `hashed_key` has been written for them.
+    fn cap_user_sessions(&self, sessions: &mut SessionsMap, hashed_key: &HashedKey) -> Vec<HashedKey> {
+        return match self.max_sessions_per_user {
+            Some(cap) => sessions.cap_user(hashed_key, cap),
+            None => Vec::new(),
+        };
+    }
+
+    fn emit_evicted(&self, evicted: &[HashedKey]) {
+        for key in evicted {
+            self.emit(key, SessionEvent::Evicted);
+        }
+    }
+
+    fn emit(&self, hashed_key: &HashedKey, event: SessionEvent) {
+        METRICS.record(event);
+        tracing::info!(event = event.name(), session = %hashed_key);
+
+        // Closes the watchers of a session that is gone.
+        if matches!(event, SessionEvent::Expired | SessionEvent::Destroyed | SessionEvent::Evicted) {
+            WATCHERS.lock().unwrap().remove(hashed_key);
+        }
+        for hook in &self.hooks {
+            hook.on_event(hashed_key, event);
+        }
+    }
+
+    /// Resolves an update of `session` made from a state loaded at `version`.
+    fn resolve(
+        &self,
+        session: &Session,
+        version: u64,
+        state: SessionState,
+    ) -> Result<SessionState, UpdateError> {
+        return match self.conflict_policy {
+            ConflictPolicy::LastWriterWins => Ok(state),
+            ConflictPolicy::Reject => Err(UpdateError::Other(anyhow::anyhow!(
+                "Session has been updated since it was loaded (version {} is now {}).",
+                version,
+                session.version
+            ))),
+            // If the loaded state is too old to be in the history, nothing is removed and the
+            // update wins on every key it holds.
+            ConflictPolicy::MergeKeys => match session.state_at(version) {
+                Some(base) => Ok(merge(base, state, &session.session)),
+                None => Ok(merge(&HashMap::new(), state, &session.session)),
+            },
+        };
+    }
+
+    fn is_expired(&self, session: &Session, now: OffsetDateTime) -> bool {
+        return now > self.expires_at(session);
+    }
+
+    /// When the session expires, unless it's used again before.
+    fn expires_at(&self, session: &Session) -> OffsetDateTime {
+        let idle_end = session.last_seen + self.idle_timeout.unwrap_or(session.ttl);
+        return match self.absolute_lifetime {
+            Some(lifetime) => idle_end.min(session.created_at + lifetime),
+            None => idle_end,
+        };
+    }
+}
 
 impl SessionStore for StatefulSessions
 {
@@ -37,45 +748,176 @@ impl SessionStore for StatefulSessions
         &self,
         session_key: &SessionKey,
     ) -> Result<Option<SessionState>, LoadError> {
-        let sessions = read_sessions();
-        return Ok(sessions.get(session_key.as_ref()).map(|s| s.session.clone()));
+        let now = OffsetDateTime::now_utc();
+        let mut sessions = write_sessions();
+        let (_, hashed_key) = sessions.follow(session_key.as_ref(), now);
+        record_session(&hashed_key);
+        let Some(session) = sessions.sessions.get_mut(&hashed_key) else {
+            METRICS.loads_miss.inc();
+            tracing::debug!(event = "session.load", session = %hashed_key, result = "miss");
+            return Ok(None);
+        };
+
+        if self.is_expired(session, now) {
+            sessions.remove(&hashed_key);
+            drop(sessions);
+            self.emit(&hashed_key, SessionEvent::Expired);
+            METRICS.loads_miss.inc();
+            tracing::debug!(event = "session.load", session = %hashed_key, result = "expired");
+            return Ok(None);
+        }
+
+        session.touch();
+        METRICS.loads_hit.inc();
+        tracing::debug!(
+            event = "session.load",
+            session = %hashed_key,
+            result = "hit",
+            version = session.version,
+        );
+
+        let mut state = session.session.clone();
+        state.insert(VERSION_KEY.to_string(), session.version.to_string());
+        if !session.queued.is_empty() {
+            // Appended to the ones a previous request may have kept unread. They stay queued
+            // until the update of this request commits, so a rejected or failed one can't lose
+            // them.
+            let mut queue = state
+                .get(FLASH_QUEUE_KEY)
+                .and_then(|queue| serde_json::from_str::<Vec<Value>>(queue).ok())
+                .unwrap_or_default();
+
+            queue.extend(session.queued.iter().cloned());
+            state.insert(FLASH_QUEUE_KEY.to_string(), Value::Array(queue).to_string());
+
+            let up_to = session.dequeued + session.queued.len() as u64;
+            state.insert(QUEUED_UP_TO_KEY.to_string(), up_to.to_string());
+        }
+
+        return Ok(Some(state));
     }
 
     async fn save(
         &self,
-        session_state: SessionState,
+        mut session_state: SessionState,
         ttl: &actix_web::cookie::time::Duration,
     ) -> Result<SessionKey, SaveError> {
+        if let Some(limiter) = &self.creation_limiter {
+            // The key handed back is never stored, and its cookie is left out of the response.
+            if !acquire_for_current_client(limiter.as_ref()) {
+                return Ok(generate_session_key());
+            }
+        }
+
+        session_state.remove(VERSION_KEY);
+        session_state.remove(QUEUED_UP_TO_KEY);
+        // A new session has a new key already.
+        session_state.remove(REGENERATE_KEY);
         let session_key = generate_session_key();
+        let hashed_key = HashedKey::new(session_key.as_ref());
+        record_session(&hashed_key);
+
         let mut sessions = write_sessions();
-        sessions.insert(session_key.as_ref().to_string().into_boxed_str(), Session {
-            session: session_state,
-            ttl: ttl.clone(),
-        });
+        sessions.insert(hashed_key.clone(), Session::new(session_state, *ttl));
+        let evicted = self.cap_user_sessions(&mut sessions, &hashed_key);
+        drop(sessions);
+
+        self.emit(&hashed_key, SessionEvent::Created);
+        self.emit_evicted(&evicted);
         return Ok(session_key);
     }
 
     async fn update(
         &self,
         session_key: SessionKey,
-        session_state: SessionState,
+        mut session_state: SessionState,
         ttl: &actix_web::cookie::time::Duration,
     ) -> Result<SessionKey, UpdateError> {
+        // States without a version (e.g. cleared by the handler) can't be checked for
+        // conflicts, and simply replace the stored one.
+        let loaded_version = session_state
+            .remove(VERSION_KEY)
+            .and_then(|version| version.parse::<u64>().ok());
+        let regenerate = session_state.remove(REGENERATE_KEY).is_some();
+        let queued_up_to = session_state
+            .remove(QUEUED_UP_TO_KEY)
+            .and_then(|up_to| up_to.parse::<u64>().ok());
+
+        let now = OffsetDateTime::now_utc();
         let mut sessions = write_sessions();
-        if !sessions.contains_key(session_key.as_ref()) {
-            sessions.insert(
-                session_key.as_ref().to_string().into_boxed_str(),
-                Session { session: session_state, ttl: ttl.clone() }
-            );
-            return Ok(session_key);
-        }
+        let (current_key, hashed_key) = sessions.follow(session_key.as_ref(), now);
+        record_session(&hashed_key);
+        match sessions.sessions.get_mut(&hashed_key) {
+            // The session has been removed since it was loaded (revoked, evicted...): it's saved
+            // again, but never signed in, so that revoking a session can't be undone by a request
+            // in flight.
+            None => {
+                for key in IDENTITY_KEYS {
+                    session_state.remove(key);
+                }
+
+                sessions.insert(hashed_key.clone(), Session::new(session_state, *ttl));
+                let evicted = self.cap_user_sessions(&mut sessions, &hashed_key);
+                drop(sessions);
+
+                self.emit(&hashed_key, SessionEvent::Created);
+                self.emit_evicted(&evicted);
+            },
+            Some(session) => {
+                let state = match loaded_version {
+                    Some(version) if version != session.version => {
+                        self.resolve(session, version, session_state)?
+                    },
+                    _ => session_state,
+                };
+
+                let keep_history = self.conflict_policy == ConflictPolicy::MergeKeys;
+                let previous_user = session.user_id.clone();
+                session.write(state, keep_history);
+                session.ttl = *ttl;
+                if let Some(up_to) = queued_up_to {
+                    session.dequeue(up_to);
+                }
+
+                // Only counted once the conflict has been resolved, so rejected updates aren't.
+                METRICS.updates.inc();
+                tracing::debug!(
+                    event = "session.update",
+                    session = %hashed_key,
+                    loaded_version,
+                    version = session.version,
+                );
+
+                let rotation = self.key_rotation
+                    .filter(|rotation| now - session.key_issued_at >= rotation.interval);
 
-        *sessions.get_mut(session_key.as_ref()).unwrap() = Session {
-            session: session_state,
-            ttl: ttl.clone()
+                sessions.reindex(&hashed_key, previous_user);
+                let evicted = self.cap_user_sessions(&mut sessions, &hashed_key);
+
+                let moved = match (regenerate, rotation) {
+                    (true, _) => sessions.rekey(&hashed_key, now),
+                    (false, Some(rotation)) => sessions.rotate(&current_key, &hashed_key, rotation.grace, now),
+                    (false, None) => None,
+                };
+
+                if let Some((new_key, new_hashed_key)) = moved {
+                    drop(sessions);
+
+                    record_session(&new_hashed_key);
+                    move_watchers(&hashed_key, &new_hashed_key);
+                    self.emit_evicted(&evicted);
+                    self.emit(&new_hashed_key, SessionEvent::Renewed);
+                    return SessionKey::try_from(new_key)
+                        .map_err(|err| UpdateError::Other(anyhow::Error::from(err)));
+                }
+
+                drop(sessions);
+                self.emit_evicted(&evicted);
+            },
         };
 
-        return Ok(session_key);
+        return SessionKey::try_from(current_key)
+            .map_err(|err| UpdateError::Other(anyhow::Error::from(err)));
     }
 
     async fn update_ttl(
@@ -84,9 +926,13 @@ impl SessionStore for StatefulSessions
         ttl: &actix_web::cookie::time::Duration,
     ) -> Result<(), anyhow::Error> {
         let mut sessions = write_sessions();
-        match sessions.get_mut(session_key.as_ref()) {
+        let (_, hashed_key) = sessions.follow(session_key.as_ref(), OffsetDateTime::now_utc());
+        match sessions.sessions.get_mut(&hashed_key) {
             None => return Err(anyhow::Error::msg("Session does not exist.")),
-            Some(session) => { (*session).ttl = ttl.clone(); },
+            Some(session) => {
+                session.ttl = *ttl;
+                session.touch();
+            },
         };
         
         return Ok(());
@@ -94,10 +940,224 @@ impl SessionStore for StatefulSessions
 
     async fn delete(&self, session_key: &SessionKey) -> Result<(), anyhow::Error> {
         let mut sessions = write_sessions();
-        if sessions.contains_key(session_key.as_ref()) {
-            sessions.remove(session_key.as_ref());
+        let (_, hashed_key) = sessions.follow(session_key.as_ref(), OffsetDateTime::now_utc());
+        let removed = sessions.remove(&hashed_key);
+        drop(sessions);
+
+        if removed.is_some() {
+            self.emit(&hashed_key, SessionEvent::Destroyed);
         }
 
         return Ok(());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(entries: &[(&str, &str)]) -> SessionState {
+        return entries
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+    }
+
+    #[test]
+    fn merge_applies_only_the_keys_the_update_changed() {
+        let base = state(&[("a", "1"), ("b", "1"), ("c", "1")]);
+        let theirs = state(&[("a", "1"), ("b", "2"), ("c", "1"), ("d", "2")]);
+        let ours = state(&[("a", "3"), ("b", "1")]);
+
+        let merged = merge(&base, ours, &theirs);
+        assert_eq!(merged, state(&[("a", "3"), ("b", "2"), ("d", "2")]));
+    }
+
+    #[test]
+    fn merge_takes_our_value_on_keys_changed_on_both_sides() {
+        let base = state(&[("a", "1")]);
+        let merged = merge(&base, state(&[("a", "3")]), &state(&[("a", "2")]));
+
+        assert_eq!(merged, state(&[("a", "3")]));
+    }
+
+    #[test]
+    fn updates_from_a_version_out_of_history_win_every_key_they_hold() {
+        let store = StatefulSessions::new();
+        let mut session = Session::new(state(&[("a", "0"), ("b", "0")]), Duration::minutes(5));
+        for version in 1..=HISTORY_LEN + 2 {
+            session.write(state(&[("a", "0"), ("b", &version.to_string())]), true);
+        }
+
+        assert!(session.state_at(0).is_none());
+
+        let resolved = store.resolve(&session, 0, state(&[("a", "1")])).ok().unwrap();
+        assert_eq!(resolved, state(&[("a", "1"), ("b", &(HISTORY_LEN + 2).to_string())]));
+    }
+
+    #[test]
+    fn updates_from_a_version_in_history_are_merged() {
+        let store = StatefulSessions::new();
+        let mut session = Session::new(state(&[("a", "0"), ("b", "0")]), Duration::minutes(5));
+        session.write(state(&[("a", "0"), ("b", "1")]), true);
+
+        let resolved = store.resolve(&session, 0, state(&[("a", "1"), ("b", "0")])).ok().unwrap();
+        assert_eq!(resolved, state(&[("a", "1"), ("b", "1")]));
+    }
+
+    #[test]
+    fn stale_updates_are_rejected_by_the_reject_policy() {
+        let store = StatefulSessions::new().conflict_policy(ConflictPolicy::Reject);
+        let mut session = Session::new(state(&[("a", "0")]), Duration::minutes(5));
+        session.write(state(&[("a", "1")]), false);
+
+        assert!(store.resolve(&session, 0, state(&[("a", "2")])).is_err());
+    }
+
+    fn rotated_map(now: OffsetDateTime) -> (SessionsMap, String, HashedKey) {
+        let mut sessions = SessionsMap::default();
+        let hashed_key = HashedKey::new("old");
+        sessions.insert(hashed_key.clone(), Session::new(state(&[("a", "1")]), Duration::minutes(5)));
+
+        let (new_key, new_hashed_key) = sessions
+            .rotate("old", &hashed_key, Duration::minutes(1), now)
+            .unwrap();
+
+        assert!(sessions.sessions.contains_key(&new_hashed_key));
+        assert!(!sessions.sessions.contains_key(&hashed_key));
+        return (sessions, new_key, new_hashed_key);
+    }
+
+    #[test]
+    fn rotated_keys_are_followed_within_the_grace_window() {
+        let now = OffsetDateTime::now_utc();
+        let (mut sessions, new_key, new_hashed_key) = rotated_map(now);
+
+        let (current_key, hashed_key) = sessions.follow("old", now + Duration::seconds(30));
+        assert_eq!(current_key, new_key);
+        assert!(hashed_key == new_hashed_key);
+    }
+
+    #[test]
+    fn rotated_keys_are_not_followed_once_the_grace_window_is_over() {
+        let now = OffsetDateTime::now_utc();
+        let (mut sessions, _, _) = rotated_map(now);
+
+        let (current_key, hashed_key) = sessions.follow("old", now + Duration::minutes(2));
+        assert_eq!(current_key, "old");
+        assert!(hashed_key == HashedKey::new("old"));
+        assert!(sessions.rotated.is_empty());
+    }
+
+    #[test]
+    fn keys_rotated_twice_are_followed_to_the_last_one() {
+        let now = OffsetDateTime::now_utc();
+        let (mut sessions, new_key, new_hashed_key) = rotated_map(now);
+        let (last_key, _) = sessions
+            .rotate(&new_key, &new_hashed_key, Duration::minutes(1), now)
+            .unwrap();
+
+        let (current_key, _) = sessions.follow("old", now + Duration::seconds(30));
+        assert_eq!(current_key, last_key);
+    }
+
+    #[actix_web::test]
+    async fn sessions_refused_by_the_limiter_are_not_stored() {
+        let limiter = Arc::new(crate::rate_limit::FixedWindowLimiter::new(0, std::time::Duration::from_secs(60)));
+        let store = StatefulSessions::new().limit_creation(limiter);
+        let client = crate::rate_limit::CurrentClient {
+            key: "client".into(),
+            limited: std::cell::Cell::new(false),
+        };
+
+        let (session_key, limited) = crate::rate_limit::CURRENT_CLIENT
+            .scope(client, async {
+                let session_key = store.save(state(&[("a", "1")]), &Duration::minutes(5)).await.unwrap();
+                (session_key, crate::rate_limit::CURRENT_CLIENT.with(|client| client.limited.get()))
+            })
+            .await;
+
+        assert!(limited);
+        assert!(store.load(&session_key).await.unwrap().is_none());
+    }
+
+    #[actix_web::test]
+    async fn regenerated_sessions_keep_their_creation_time_and_are_renewed() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        let store = StatefulSessions::new().hook(move |hashed_key: &HashedKey, event| {
+            recorded.lock().unwrap().push((hashed_key.clone(), event));
+        });
+        let ttl = Duration::minutes(5);
+
+        let key = store.save(state(&[("a", "\"1\"")]), &ttl).await.unwrap();
+        let old_hashed_key = HashedKey::new(key.as_ref());
+        let created_at = |hashed_key: &HashedKey| {
+            store.list()
+                .into_iter()
+                .find(|session| session.hashed_key == *hashed_key)
+                .map(|session| session.created_at)
+        };
+        let old_created_at = created_at(&old_hashed_key).unwrap();
+
+        let mut loaded = store.load(&key).await.unwrap().unwrap();
+        loaded.insert(REGENERATE_KEY.to_string(), "true".into());
+        let old_key = key.as_ref().to_string();
+        let new_key = store.update(key, loaded, &ttl).await.unwrap();
+        assert_ne!(new_key.as_ref(), old_key);
+
+        let new_hashed_key = HashedKey::new(new_key.as_ref());
+        assert!(store.load(&SessionKey::try_from(old_key).unwrap()).await.unwrap().is_none());
+        let loaded = store.load(&new_key).await.unwrap().unwrap();
+        assert_eq!(loaded["a"], "\"1\"");
+        assert!(!loaded.contains_key(REGENERATE_KEY));
+        assert_eq!(created_at(&new_hashed_key), Some(old_created_at));
+
+        let events = events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(hashed_key, _)| *hashed_key == old_hashed_key || *hashed_key == new_hashed_key)
+            .map(|(_, event)| *event)
+            .collect::<Vec<_>>();
+        assert_eq!(events, vec![SessionEvent::Created, SessionEvent::Renewed]);
+    }
+
+    #[actix_web::test]
+    async fn enqueued_flash_is_kept_until_the_update_delivering_it_commits() {
+        let store = StatefulSessions::new().conflict_policy(ConflictPolicy::Reject);
+        let ttl = Duration::minutes(5);
+        let key = store.save(state(&[("a", "\"1\"")]), &ttl).await.unwrap();
+        let hashed_key = HashedKey::new(key.as_ref());
+
+        let concurrent = store.load(&key).await.unwrap().unwrap();
+        assert!(store.enqueue_flash(&hashed_key, "hello"));
+        let mut delivering = store.load(&key).await.unwrap().unwrap();
+        assert_eq!(delivering[FLASH_QUEUE_KEY], r#"["hello"]"#);
+
+        // The delivering update is rejected, as the session has been written to since.
+        let key = store.update(key, concurrent, &ttl).await.unwrap();
+        delivering.remove(FLASH_QUEUE_KEY);
+        let key_copy = SessionKey::try_from(key.as_ref().to_string()).unwrap();
+        assert!(store.update(key_copy, delivering, &ttl).await.is_err());
+
+        let mut delivering = store.load(&key).await.unwrap().unwrap();
+        assert_eq!(delivering[FLASH_QUEUE_KEY], r#"["hello"]"#);
+
+        delivering.remove(FLASH_QUEUE_KEY);
+        let key = store.update(key, delivering, &ttl).await.unwrap();
+        assert!(!store.load(&key).await.unwrap().unwrap().contains_key(FLASH_QUEUE_KEY));
+    }
+
+    #[test]
+    fn watchers_that_went_away_are_dropped_on_reconnect() {
+        let store = StatefulSessions::new();
+        let hashed_key = HashedKey::new(generate_session_key().as_ref());
+        for _ in 0..100 {
+            drop(store.watch(&hashed_key));
+        }
+
+        let _receiver = store.watch(&hashed_key);
+        assert_eq!(WATCHERS.lock().unwrap()[&hashed_key].len(), 1);
+    }
+}
@@ -0,0 +1,94 @@
+use std::cell::RefCell;
+use std::future::{ready, Ready};
+use std::time::Instant;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::Error;
+use futures_util::future::LocalBoxFuture;
+use tracing::{field, info_span, Instrument, Span};
+use crate::session_hash::HashedKey;
+
+tokio::task_local! {
+    static CURRENT_SESSION: RefCell<Option<HashedKey>>;
+}
+
+/// Runs every request in a `request` span, so that everything logged while handling it (store
+/// and flash events included) carries its method, path and, once the store has seen it, the
+/// hashed key of its session. The key is also handed over to `current_session`.
+///
+/// It must wrap the session middleware, i.e. be registered after it.
+pub struct TraceRequests;
+
+impl<S, B> Transform<S, ServiceRequest> for TraceRequests
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = TraceRequestsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(TraceRequestsMiddleware { service }))
+    }
+}
+
+pub struct TraceRequestsMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for TraceRequestsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let span = info_span!(
+            "request",
+            method = %req.method(),
+            path = %req.path(),
+            session = field::Empty,
+        );
+
+        let started_at = Instant::now();
+        let fut: <S as Service<ServiceRequest>>::Future = span.in_scope(|| self.service.call(req));
+
+        Box::pin(CURRENT_SESSION.scope(RefCell::new(None), async move {
+            let res = fut.await;
+            match &res {
+                Ok(res) => tracing::info!(
+                    event = "request.done",
+                    status = res.status().as_u16(),
+                    elapsed_ms = started_at.elapsed().as_millis() as u64,
+                ),
+                Err(err) => tracing::warn!(event = "request.failed", error = %err),
+            };
+
+            return res;
+        }.instrument(span)))
+    }
+}
+
+/// Tags the current request span with the session it's using.
+pub fn record_session(hashed_key: &HashedKey) {
+    Span::current().record("session", field::display(hashed_key));
+    let _ = CURRENT_SESSION.try_with(|current| *current.borrow_mut() = Some(hashed_key.clone()));
+}
+
+/// The hashed key of the session the store has loaded for the request being handled, if any.
+/// Outside of a request wrapped by `TraceRequests`, there's none.
+pub fn current_session() -> Option<HashedKey> {
+    return CURRENT_SESSION
+        .try_with(|current| current.borrow().clone())
+        .ok()
+        .flatten();
+}
@@ -0,0 +1,189 @@
+use actix_web::guard::{Guard, GuardContext};
+use actix_web::http::{header::ACCEPT, StatusCode};
+use actix_web::web::{self, Redirect};
+use actix_web::{get, post, HttpRequest, HttpResponse, Responder, Scope};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use subtle::ConstantTimeEq;
+
+use crate::session_hash::HashedId;
+use crate::sessions::{Sessions, VersionedSession};
+use crate::HBS;
+
+/// The sessions admin: lists, inspects, messages and revokes the stored sessions, as HTML or, when asked
+/// for with `Accept: application/json`, as JSON.
+///
+/// It's only ever reachable through `guard`: requests it rejects get a plain 404, as if there
+/// was no admin at all.
+pub fn scope(path: &str, guard: impl Guard + 'static) -> Scope {
+    return web::scope(path)
+        .guard(guard)
+        .service(list)
+        .service(revoke_all)
+        .service(inspect)
+        .service(flash)
+        .service(revoke);
+}
+
+/// Lets through requests whose `X-Admin-Token` header holds the given token.
+pub struct AdminToken(String);
+
+impl AdminToken {
+    pub fn new(token: String) -> Self {
+        return AdminToken(token);
+    }
+}
+
+impl Guard for AdminToken {
+    fn check(&self, ctx: &GuardContext<'_>) -> bool {
+        return ctx.head()
+            .headers()
+            .get("x-admin-token")
+            .is_some_and(|token| token.as_bytes().ct_eq(self.0.as_bytes()).into());
+    }
+}
+
+fn wants_json(req: &HttpRequest) -> bool {
+    return req.headers()
+        .get(ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/json"));
+}
+
+/// Responds with `data` as JSON, or renders it with `template`. Pages also get the `admin` list
+/// URL, and stored values as JSON text.
+fn respond(req: &HttpRequest, hb: &HBS<'_>, template: &str, mut data: Value) -> HttpResponse {
+    if wants_json(req) {
+        return HttpResponse::Ok().json(data);
+    }
+
+    data["admin"] = json!(list_url(req));
+    if let Some(map) = data.get_mut("map").and_then(Value::as_object_mut) {
+        for value in map.values_mut() {
+            *value = json!(value.to_string());
+        }
+    }
+
+    return match hb.render(template, &data) {
+        Ok(body) => HttpResponse::Ok().content_type("text/html").body(body),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    };
+}
+
+fn summary(hashed_id: &HashedId, session: &VersionedSession) -> Value {
+    let mut keys = session.map.keys().map(|key| key.to_string()).collect::<Vec<_>>();
+    keys.sort();
+
+    return json!({
+        "id": hashed_id.to_string(),
+        "version": session.version,
+        "bytes": session.bytes(),
+        "keys": keys,
+        "age": session.age().as_secs(),
+        // Sessions never expire in the store.
+        "ttl": Value::Null,
+    });
+}
+
+#[derive(Deserialize)]
+struct Filter {
+    /// Only lists the sessions holding this key.
+    key: Option<String>,
+}
+
+#[get("", name = "admin_sessions")]
+async fn list(req: HttpRequest, hb: HBS<'_>, filter: web::Query<Filter>) -> impl Responder {
+    let sessions = Sessions::all();
+    let mut listed = sessions
+        .iter()
+        .filter(|(_, session)| match &filter.key {
+            Some(key) => session.map.contains_key(key.as_str()),
+            None => true,
+        })
+        .map(|(hashed_id, session)| summary(hashed_id, session))
+        .collect::<Vec<_>>();
+    drop(sessions);
+
+    listed.sort_by_key(|session| session["age"].as_u64());
+    return respond(&req, &hb, "admin/sessions", json!({
+        "key": filter.key,
+        "sessions": listed,
+    }));
+}
+
+#[get("/{id}")]
+async fn inspect(req: HttpRequest, hb: HBS<'_>, id: web::Path<String>) -> impl Responder {
+    let Some(hashed_id) = HashedId::parse(&id) else {
+        return HttpResponse::NotFound().finish();
+    };
+
+    let sessions = Sessions::all();
+    let Some(session) = sessions.get(&hashed_id) else {
+        return HttpResponse::NotFound().finish();
+    };
+
+    let mut data = summary(&hashed_id, session);
+    data["map"] = json!(session.map);
+    drop(sessions);
+
+    return respond(&req, &hb, "admin/session", data);
+}
+
+#[derive(Deserialize)]
+struct Message {
+    message: String,
+}
+
+/// Enqueues a flash message for the session's next request.
+#[post("/{id}/flash")]
+async fn flash(req: HttpRequest, id: web::Path<String>, form: web::Form<Message>) -> impl Responder {
+    let message = form.into_inner().message;
+    let enqueued = match HashedId::parse(&id) {
+        Some(hashed_id) if !message.trim().is_empty() => Sessions::enqueue_flash(&hashed_id, message),
+        _ => Ok(false),
+    };
+
+    let enqueued = match enqueued {
+        Ok(enqueued) => enqueued,
+        Err(err) => return HttpResponse::PayloadTooLarge().body(err.to_string()),
+    };
+
+    if wants_json(&req) {
+        return HttpResponse::Ok().json(json!({"enqueued": enqueued}));
+    }
+
+    return back_to_list(&req);
+}
+
+#[post("/{id}/revoke")]
+async fn revoke(req: HttpRequest, id: web::Path<String>) -> impl Responder {
+    let revoked = HashedId::parse(&id).is_some_and(|hashed_id| Sessions::revoke(&hashed_id));
+    if wants_json(&req) {
+        return HttpResponse::Ok().json(json!({"revoked": revoked}));
+    }
+
+    return back_to_list(&req);
+}
+
+#[post("/revoke-all")]
+async fn revoke_all(req: HttpRequest) -> impl Responder {
+    let revoked = Sessions::revoke_all();
+    if wants_json(&req) {
+        return HttpResponse::Ok().json(json!({"revoked": revoked}));
+    }
+
+    return back_to_list(&req);
+}
+
+fn list_url(req: &HttpRequest) -> String {
+    return req.url_for_static("admin_sessions")
+        .map(|url| url.path().to_string())
+        .unwrap_or_else(|_| "/".into());
+}
+
+fn back_to_list(req: &HttpRequest) -> HttpResponse {
+    return Redirect::to(list_url(req))
+        .using_status_code(StatusCode::SEE_OTHER)
+        .respond_to(req)
+        .map_into_boxed_body();
+}
@@ -0,0 +1,123 @@
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue, ACCEPT, CACHE_CONTROL};
+use actix_web::web::ReqData;
+use actix_web::{get, HttpResponse, Responder};
+use serde_json::{json, Map, Value};
+
+use crate::sessions::{Session, FLASH_KEY, FLASH_QUEUE_KEY};
+
+/// The header flash messages are delivered in to JSON clients.
+pub const FLASH_HEADER: HeaderName = HeaderName::from_static("x-flash");
+/// The htmx header flash messages are delivered in, as a `flash` event.
+pub const HX_TRIGGER: HeaderName = HeaderName::from_static("hx-trigger");
+
+/// A client that doesn't render pages, and so needs flash messages handed over alongside the
+/// response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlashClient {
+    /// An htmx request, which gets them as an `HX-Trigger` event. Boosted requests are page
+    /// navigations, and aren't one.
+    Htmx,
+    /// A request asking for JSON (`Accept: application/json`), e.g. a `fetch` from a SPA.
+    Json,
+}
+
+impl FlashClient {
+    pub fn of(headers: &HeaderMap) -> Option<Self> {
+        let is_set = |name: &str| headers.get(name).is_some_and(|value| value == "true");
+        if is_set("HX-Request") && !is_set("HX-Boosted") {
+            return Some(FlashClient::Htmx);
+        }
+
+        let wants_json = headers
+            .get(ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .is_some_and(|accept| accept.contains("application/json"));
+
+        return match wants_json {
+            true => Some(FlashClient::Json),
+            false => None,
+        };
+    }
+}
+
+/// Hands the pending flash and enqueued flash messages the handler hasn't read over to `client`,
+/// in the response headers, as `{"flash": .., "queued": [..]}`. They're taken from the store
+/// even when the handler hasn't touched the session, as the client won't render the next page.
+pub fn deliver(session: &Session, client: FlashClient, headers: &mut HeaderMap) {
+    let mut taken = session.take_flash();
+    let mut payload = Map::new();
+    if let Some(flash) = taken.remove(FLASH_KEY) {
+        payload.insert("flash".into(), flash);
+    }
+
+    if let Some(queued) = taken.remove(FLASH_QUEUE_KEY) {
+        payload.insert("queued".into(), queued);
+    }
+
+    if payload.is_empty() {
+        return;
+    }
+
+    tracing::info!(event = "flash.delivered", client = ?client);
+    let payload = Value::Object(payload);
+    let value = match client {
+        FlashClient::Htmx => hx_trigger(headers.get(HX_TRIGGER), payload),
+        FlashClient::Json => header_json(&payload),
+    };
+
+    let name = match client {
+        FlashClient::Htmx => HX_TRIGGER,
+        FlashClient::Json => FLASH_HEADER,
+    };
+
+    headers.insert(name, value);
+}
+
+/// `value` as a header value. Non-ASCII and control characters are escaped (they can only be in
+/// strings), so it's still the same JSON for clients decoding headers as Latin-1, and any value
+/// can be sent.
+fn header_json(value: &Value) -> HeaderValue {
+    let mut ascii = String::new();
+    for char in value.to_string().chars() {
+        match char.is_ascii() && !char.is_ascii_control() {
+            true => ascii.push(char),
+            false => {
+                for unit in char.encode_utf16(&mut [0; 2]) {
+                    ascii.push_str(&format!("\\u{:04x}", unit));
+                }
+            },
+        };
+    }
+
+    return HeaderValue::from_str(&ascii).expect("only visible ASCII characters are left");
+}
+
+/// Adds the `flash` event to the `HX-Trigger` events the handler may have set already.
+fn hx_trigger(existing: Option<&HeaderValue>, payload: Value) -> HeaderValue {
+    let existing = existing.and_then(|value| value.to_str().ok());
+    let mut events = match existing.map(serde_json::from_str::<Map<String, Value>>) {
+        None => Map::new(),
+        Some(Ok(events)) => events,
+        // A plain list of event names.
+        Some(Err(_)) => existing
+            .unwrap_or_default()
+            .split(',')
+            .map(|event| (event.trim().to_string(), Value::Null))
+            .collect(),
+    };
+
+    events.insert("flash".into(), payload);
+    return header_json(&Value::Object(events));
+}
+
+/// `GET /_flash`: drains the pending flash and enqueued flash messages, for clients that would rather ask for it than read it
+/// off other responses.
+#[get("/_flash")]
+pub async fn drain(session: ReqData<Session>) -> impl Responder {
+    return HttpResponse::Ok()
+        .insert_header((CACHE_CONTROL, "no-store"))
+        .json(json!({
+            "flash": session.get("flash"),
+            "queued": session.get(FLASH_QUEUE_KEY),
+        }));
+}
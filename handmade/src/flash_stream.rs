@@ -0,0 +1,57 @@
+use std::pin::pin;
+use std::time::Duration;
+
+use actix_web::error::ErrorUnauthorized;
+use actix_web::http::header::CACHE_CONTROL;
+use actix_web::rt::time::{interval, Interval};
+use actix_web::web::{Bytes, ReqData};
+use actix_web::{get, Error, HttpResponse};
+use futures_util::future::{select, Either};
+use futures_util::stream;
+use serde_json::Value;
+use tokio::sync::mpsc::Receiver;
+
+use crate::session_hash::HashedId;
+use crate::sessions::{Session, Sessions};
+
+/// How often an idle stream gets a comment, so that proxies don't close it.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+fn event(payload: &Value) -> Bytes {
+    return Bytes::from(format!("event: flash\ndata: {}\n\n", payload));
+}
+
+/// Waits for the next pushed message, sending a comment whenever the stream has been idle for
+/// too long. Ends once the session is gone.
+async fn next_event(
+    (mut messages, mut keep_alive): (Receiver<Value>, Interval),
+) -> Option<(Result<Bytes, Error>, (Receiver<Value>, Interval))> {
+    let next = match select(pin!(messages.recv()), pin!(keep_alive.tick())).await {
+        Either::Left((Some(payload), _)) => Some(event(&payload)),
+        Either::Left((None, _)) => None,
+        Either::Right(_) => Some(Bytes::from_static(b": keep-alive\n\n")),
+    };
+
+    return next.map(|bytes| (Ok(bytes), (messages, keep_alive)));
+}
+
+/// `GET /_flash/stream`: streams, as Server-Sent Events named `flash`, the flash messages pushed
+/// to the session the request comes with (see `Sessions::push_flash`). Without a stored session,
+/// it's a `401`. The session isn't read, so the flash it holds is left for the next page.
+#[get("/_flash/stream")]
+pub async fn subscribe(session: ReqData<Session>) -> Result<HttpResponse, Error> {
+    let hashed_id = session
+        .id()
+        .map(|session_id| HashedId::new(&session_id))
+        .filter(|hashed_id| Sessions::all().get(hashed_id).is_some())
+        .ok_or_else(|| ErrorUnauthorized("No session to stream the flash messages of."))?;
+
+    let messages = Sessions::watch(&hashed_id);
+    tracing::info!(event = "flash.stream_opened", session = %hashed_id);
+
+    let events = stream::unfold((messages, interval(KEEP_ALIVE)), next_event);
+    return Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        .streaming(events));
+}
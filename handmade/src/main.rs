@@ -1,25 +1,38 @@
+#![allow(clippy::needless_return, clippy::upper_case_acronyms)]
+
 use std::io;
-use actix_web::{body::BoxBody, dev::ServiceResponse, get, http::{header::ContentType, StatusCode}, middleware::{ErrorHandlerResponse, ErrorHandlers}, web::{self, Data, Html, Redirect, ReqData}, App, HttpResponse, HttpServer, Responder};
+use std::sync::Arc;
+use actix_web::{body::BoxBody, dev::ServiceResponse, get, post, http::{header::ContentType, StatusCode}, middleware::{ErrorHandlerResponse, ErrorHandlers}, web::{self, Data, Html, Redirect, ReqData}, App, HttpResponse, HttpServer, Responder};
 use handlebars::{DirectorySourceOptions, Handlebars};
+use rate_limit::{FixedWindowLimiter, SessionRateLimiter};
 use serde_json::json;
-use sessions::Session;
+use session_hash::HashedId;
+use session_hooks::SessionEvent;
+use session_middleware::CheckSession;
+use session_transport::{BearerTransport, CookieTransport, HeaderTransport};
+use sessions::{Session, Sessions, SessionsLimits, FLASH_QUEUE_KEY};
+use tracing_subscriber::EnvFilter;
 
 mod sessions;
 mod session_middleware;
+mod session_hash;
+mod session_hooks;
+mod rate_limit;
+mod session_transport;
+mod admin;
+mod metrics;
+mod flash_delivery;
+mod flash_stream;
 
-type HBS<'a> = Data<Handlebars<'a>>;
+pub type HBS<'a> = Data<Handlebars<'a>>;
 
 #[get("/foo")]
 async fn foo(hb: HBS<'_>, session: ReqData<Session>) -> impl Responder {
-    let session = session.into_inner();
-    let flash = match &session.map {
-        Some(map) => map.get("flash"),
-        None => None,
-    };
-
+    let flash = session.get("flash");
     let body = hb
         .render("foo", &json!({
-            "flash": flash
+            "flash": flash,
+            "queued": session.get(FLASH_QUEUE_KEY),
         }))
         .unwrap();
 
@@ -29,33 +42,108 @@ async fn foo(hb: HBS<'_>, session: ReqData<Session>) -> impl Responder {
 #[get("/forward")]
 async fn forward_session(session: ReqData<Session>) -> impl Responder {
     let session = session.into_inner();
-    sessions::Sessions::forward(session);
+    if let Err(err) = Sessions::forward(session) {
+        tracing::warn!(event = "session.forward_failed", error = %err);
+    }
+
     return Redirect::new("/forward", "/foo");
 }
 
 #[get("/redirect/forward")]
 async fn redirect_to_forward(session: ReqData<Session>) -> impl Responder {
-    sessions::Sessions::store(
-        &session.id(),
+    let stored = session.insert(
         "flash",
         serde_json::to_value("Flash message from forward redirect!".to_string()).unwrap()
     );
 
+    if let Err(err) = stored {
+        tracing::warn!(event = "session.insert_failed", key = "flash", error = %err);
+    }
+
     return Redirect::new("/redirect/forward", "/forward");
 }
 
 
 #[get("/redirect")]
 async fn redirect(session: ReqData<Session>) -> impl Responder {
-    sessions::Sessions::store(
-        &session.id(),
+    let stored = session.insert(
         "flash",
         serde_json::to_value("Flash message from redirect!".to_string()).unwrap()
     );
 
+    if let Err(err) = stored {
+        tracing::warn!(event = "session.insert_failed", key = "flash", error = %err);
+    }
+
     return Redirect::new("/redirect", "/foo");
 }
 
+/// Moves the session to a new id, as a login would. The new id is sent back in `X-Session-Id`.
+///
+/// Like `/export`, it refuses session ids sent in the cookie, so another site can't make a
+/// browser change its session.
+#[post("/session/regenerate")]
+async fn regenerate_session(session: ReqData<Session>) -> impl Responder {
+    if session.is_ambient() {
+        return HttpResponse::Forbidden().body("Send the session id in the X-Session-Id header.");
+    }
+
+    session.regenerate();
+    return HttpResponse::NoContent().finish();
+}
+
+/// Destroys the session, as a logout would. Refuses session ids sent in the cookie, so another
+/// site can't sign a browser out.
+#[post("/session/invalidate")]
+async fn invalidate_session(session: ReqData<Session>) -> impl Responder {
+    if session.is_ambient() {
+        return HttpResponse::Forbidden().body("Send the session id in the X-Session-Id header.");
+    }
+
+    session.invalidate();
+    return HttpResponse::NoContent().finish();
+}
+
+/// Pretends to run a long export, and tells the session's open pages once it's done, or its next
+/// request if none is open.
+///
+/// Its side effect goes beyond the session, so the session id must come in the `X-Session-Id`
+/// header or as a bearer token: another site can't make a browser send these, unlike a cookie.
+#[post("/export")]
+async fn export(session: ReqData<Session>) -> impl Responder {
+    if session.is_ambient() {
+        return HttpResponse::Forbidden().body("Send the session id in the X-Session-Id header.");
+    }
+
+    let Some(session_id) = session.id() else {
+        return HttpResponse::Unauthorized().finish();
+    };
+
+    let hashed_id = HashedId::new(&session_id);
+    actix_web::rt::spawn(async move {
+        actix_web::rt::time::sleep(std::time::Duration::from_secs(3)).await;
+        let message = "Your export is ready.";
+        if Sessions::push_flash(&hashed_id, message) == 0 {
+            if let Err(err) = Sessions::enqueue_flash(&hashed_id, message) {
+                tracing::warn!(event = "session.insert_failed", key = FLASH_QUEUE_KEY, error = %err);
+            }
+        }
+    });
+
+    return HttpResponse::Accepted().finish();
+}
+
+#[get("/metrics")]
+async fn metrics_endpoint() -> impl Responder {
+    let sessions = Sessions::all();
+    let (live_sessions, bytes_stored) = (sessions.len(), sessions.bytes());
+    drop(sessions);
+
+    return HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::METRICS.render(live_sessions, bytes_stored));
+}
+
 #[get("/")]
 async fn index(hb: HBS<'_>) -> impl Responder {
     let body = hb.
@@ -69,6 +157,8 @@ async fn index(hb: HBS<'_>) -> impl Responder {
 
 #[actix_web::main]
 async fn main() -> io::Result<()> {
+    init_tracing();
+
     let mut handlebars = Handlebars::new();
     handlebars
         .register_templates_directory(
@@ -78,16 +168,60 @@ async fn main() -> io::Result<()> {
         .unwrap();
     let handlebars_ref = web::Data::new(handlebars);
 
+    Sessions::set_limits(SessionsLimits {
+        max_sessions: Some(10_000),
+        max_total_bytes: Some(64 * 1024 * 1024),
+        max_session_bytes: Some(16 * 1024),
+    });
+
+    // An audit trail of the sessions ending, apart from the request logs (`RUST_LOG=audit=info`).
+    Sessions::register_hook(|hashed_id: &HashedId, event: SessionEvent| {
+        if matches!(event, SessionEvent::Destroyed | SessionEvent::Evicted) {
+            tracing::info!(target: "audit", event = event.name(), session = %hashed_id);
+        }
+    });
+
+    let creation_limiter: Arc<dyn SessionRateLimiter> = Arc::new(
+        FixedWindowLimiter::new(20, std::time::Duration::from_secs(60))
+    );
+
+    // Only set it behind a proxy that overwrites the forwarded-for headers.
+    let trust_forwarded_for = std::env::var("TRUST_FORWARDED_FOR").is_ok_and(|trust| trust == "true");
+
+    // The sessions admin is only mounted when a token is set for it.
+    let admin_token = std::env::var("ADMIN_TOKEN").ok().filter(|token| !token.is_empty());
+
     HttpServer::new(move || {
+        let admin = admin_token
+            .clone()
+            .map(|token| admin::scope("/admin/sessions", admin::AdminToken::new(token)));
+
         App::new()
             .wrap(error_handlers())
-            .wrap(session_middleware::CheckSession)
+            .wrap(
+                CheckSession::new()
+                    .transport(CookieTransport)
+                    .transport(HeaderTransport)
+                    .transport(BearerTransport)
+                    .limit_creation(creation_limiter.clone())
+                    .trust_forwarded_for(trust_forwarded_for)
+                    .keep_unread(true)
+            )
             .app_data(handlebars_ref.clone())
             .service(index)
             .service(foo)
             .service(redirect)
             .service(redirect_to_forward)
             .service(forward_session)
+            .service(metrics_endpoint)
+            .service(flash_delivery::drain)
+            .service(flash_stream::subscribe)
+            .service(export)
+            .service(regenerate_session)
+            .service(invalidate_session)
+            .configure(|cfg| if let Some(admin) = admin {
+                cfg.service(admin);
+            })
             .service(actix_files::Files::new("/", "./public/").prefer_utf8(true))
     })
     .workers(2)
@@ -96,6 +230,18 @@ async fn main() -> io::Result<()> {
     .await
 }
 
+/// Logs to stdout, filtered by `RUST_LOG` (`info` by default), as JSON lines when `LOG_FORMAT` is
+/// `json`.
+fn init_tracing() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+
+    match std::env::var("LOG_FORMAT").as_deref() {
+        Ok("json") => subscriber.json().init(),
+        _ => subscriber.init(),
+    };
+}
+
 fn error_handlers() -> ErrorHandlers<BoxBody> {
     ErrorHandlers::new().handler(StatusCode::NOT_FOUND, not_found)
 }
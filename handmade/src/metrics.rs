@@ -0,0 +1,117 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::session_hooks::SessionEvent;
+
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Self {
+        return Counter(AtomicU64::new(0));
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        return self.0.load(Ordering::Relaxed);
+    }
+}
+
+/// Counters of everything that happens to sessions and flash messages, since the process started.
+pub struct SessionMetrics {
+    pub created: Counter,
+    pub loads_hit: Counter,
+    pub loads_miss: Counter,
+    pub updates: Counter,
+    pub deletes: Counter,
+    pub evictions: Counter,
+    pub renewals: Counter,
+    pub unread_dropped: Counter,
+    pub unread_kept: Counter,
+    pub forward_discarded: Counter,
+    pub flash_set: Counter,
+    pub flash_consumed: Counter,
+    pub flash_dropped: Counter,
+    pub flash_kept: Counter,
+}
+
+pub static METRICS: SessionMetrics = SessionMetrics {
+    created: Counter::new(),
+    loads_hit: Counter::new(),
+    loads_miss: Counter::new(),
+    updates: Counter::new(),
+    deletes: Counter::new(),
+    evictions: Counter::new(),
+    renewals: Counter::new(),
+    unread_dropped: Counter::new(),
+    unread_kept: Counter::new(),
+    forward_discarded: Counter::new(),
+    flash_set: Counter::new(),
+    flash_consumed: Counter::new(),
+    flash_dropped: Counter::new(),
+    flash_kept: Counter::new(),
+};
+
+impl SessionMetrics {
+    /// Counts a session lifecycle event.
+    pub fn record(&self, event: SessionEvent) {
+        match event {
+            SessionEvent::Created => self.created.inc(),
+            SessionEvent::Renewed => self.renewals.inc(),
+            SessionEvent::Destroyed => self.deletes.inc(),
+            SessionEvent::Evicted => self.evictions.inc(),
+        };
+    }
+
+    /// Renders the counters, along with the store's `live_sessions` and `bytes_stored` gauges, in
+    /// the Prometheus text format.
+    pub fn render(&self, live_sessions: usize, bytes_stored: usize) -> String {
+        let mut out = String::new();
+
+        gauge(&mut out, "sessions_live", "Sessions currently stored.", live_sessions as u64);
+        gauge(&mut out, "sessions_bytes", "Approximate size of the stored sessions.", bytes_stored as u64);
+
+        counter(&mut out, "sessions_created_total", "Sessions created.", &[("", &self.created)]);
+        counter(&mut out, "sessions_loads_total", "Sessions loads, by whether the session was found.", &[
+            ("result=\"hit\"", &self.loads_hit),
+            ("result=\"miss\"", &self.loads_miss),
+        ]);
+        counter(&mut out, "sessions_updates_total", "Sessions updates.", &[("", &self.updates)]);
+        counter(&mut out, "sessions_deleted_total", "Sessions destroyed.", &[("", &self.deletes)]);
+        counter(&mut out, "sessions_evicted_total", "Sessions evicted.", &[("", &self.evictions)]);
+        counter(&mut out, "sessions_renewed_total", "Sessions given a new key.", &[("", &self.renewals)]);
+        counter(&mut out, "session_values_unread_total", "Values never read, by what happened to them.", &[
+            ("state=\"dropped\"", &self.unread_dropped),
+            ("state=\"kept\"", &self.unread_kept),
+        ]);
+        counter(
+            &mut out,
+            "session_values_forward_discarded_total",
+            "Forwarded values replaced by a parallel request.",
+            &[("", &self.forward_discarded)],
+        );
+        counter(&mut out, "flash_messages_total", "Flash messages, by what happened to them.", &[
+            ("state=\"set\"", &self.flash_set),
+            ("state=\"consumed\"", &self.flash_consumed),
+            ("state=\"dropped\"", &self.flash_dropped),
+            ("state=\"kept\"", &self.flash_kept),
+        ]);
+
+        return out;
+    }
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}");
+}
+
+fn counter(out: &mut String, name: &str, help: &str, series: &[(&str, &Counter)]) {
+    let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} counter");
+    for (labels, counter) in series {
+        let _ = match labels.is_empty() {
+            true => writeln!(out, "{name} {}", counter.get()),
+            false => writeln!(out, "{name}{{{labels}}} {}", counter.get()),
+        };
+    }
+}
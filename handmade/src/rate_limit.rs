@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use actix_web::dev::ServiceRequest;
+
+/// Decides whether a client may be given a new session.
+pub trait SessionRateLimiter: Send + Sync {
+    /// Records a new session for `client`. Returns `false` if it's over the limit, in which case
+    /// the session must not be created.
+    fn try_acquire(&self, client: &str) -> bool;
+}
+
+/// How many clients a `FixedWindowLimiter` keeps track of at most.
+const MAX_CLIENTS: usize = 10_000;
+
+/// Allows up to `max` new sessions per client in every `window`.
+///
+/// At most `MAX_CLIENTS` clients are tracked at once. When a new one comes in past that, the
+/// windows that already ended are dropped and, if that isn't enough, so is the oldest one.
+pub struct FixedWindowLimiter {
+    max: u32,
+    window: Duration,
+    windows: Mutex<HashMap<String, (Instant, u32)>>,
+}
+
+impl FixedWindowLimiter {
+    pub fn new(max: u32, window: Duration) -> Self {
+        return FixedWindowLimiter {
+            max,
+            window,
+            windows: Mutex::new(HashMap::new()),
+        };
+    }
+}
+
+impl SessionRateLimiter for FixedWindowLimiter {
+    fn try_acquire(&self, client: &str) -> bool {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap_or_else(|e| e.into_inner());
+
+        if !windows.contains_key(client) && windows.len() >= MAX_CLIENTS {
+            windows.retain(|_, (start, _)| now - *start < self.window);
+
+            if windows.len() >= MAX_CLIENTS {
+                let oldest = windows
+                    .iter()
+                    .min_by_key(|(_, (start, _))| *start)
+                    .map(|(client, _)| client.clone());
+
+                if let Some(oldest) = oldest {
+                    windows.remove(&oldest);
+                }
+            }
+        }
+
+        let (start, count) = windows.entry(client.to_string()).or_insert((now, 0));
+        if now - *start >= self.window {
+            *start = now;
+            *count = 0;
+        }
+
+        if *count >= self.max {
+            return false;
+        }
+
+        *count += 1;
+        return true;
+    }
+}
+
+/// Identifies the client behind a request: the address in `Forwarded`/`X-Forwarded-For` when
+/// `trust_forwarded_for` is set (only do so behind a proxy that overwrites them), the peer
+/// address otherwise.
+pub fn client_key(req: &ServiceRequest, trust_forwarded_for: bool) -> String {
+    if trust_forwarded_for {
+        if let Some(addr) = req.connection_info().realip_remote_addr() {
+            return addr.to_string();
+        }
+    }
+
+    return req.peer_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_else(|| "unknown".into());
+}
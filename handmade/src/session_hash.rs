@@ -0,0 +1,69 @@
+use std::fmt::{Debug, Display};
+use std::hash::{Hash, Hasher};
+use std::sync::LazyLock;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+
+/// The secret session ids are hashed with, random per process, as sessions don't outlive it.
+static SECRET: LazyLock<[u8; 32]> = LazyLock::new(rand::random);
+
+/// A keyed hash (HMAC-SHA256) of a session id, which the store is indexed by, so that neither
+/// its contents nor anything shown or logged from it hands out a usable token.
+///
+/// Equality is checked in constant time.
+#[derive(Clone)]
+pub struct HashedId([u8; 32]);
+
+impl HashedId {
+    pub fn new(session_id: &str) -> Self {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&*SECRET)
+            .expect("HMAC accepts keys of any size");
+        mac.update(session_id.as_bytes());
+        return HashedId(mac.finalize().into_bytes().into());
+    }
+
+    /// Parses the hex representation of a hashed id, as printed by `Display`.
+    pub fn parse(hex: &str) -> Option<Self> {
+        if hex.len() != 64 || !hex.is_ascii() {
+            return None;
+        }
+
+        let mut bytes = [0; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+        }
+
+        return Some(HashedId(bytes));
+    }
+}
+
+impl PartialEq for HashedId {
+    fn eq(&self, other: &Self) -> bool {
+        return self.0.ct_eq(&other.0).into();
+    }
+}
+
+impl Eq for HashedId {}
+
+impl Hash for HashedId {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+    }
+}
+
+impl Display for HashedId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+
+        return Ok(());
+    }
+}
+
+impl Debug for HashedId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return write!(f, "HashedId({})", self);
+    }
+}
@@ -0,0 +1,43 @@
+use crate::session_hash::HashedId;
+
+/// Why a hook is being notified about a session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionEvent {
+    /// A new session has been stored.
+    Created,
+    /// The session has been given a new id, keeping its data.
+    Renewed,
+    /// The session has been explicitly deleted.
+    Destroyed,
+    /// The session has been dropped to make room for others.
+    Evicted,
+}
+
+impl SessionEvent {
+    /// The name the event is logged under, e.g. `session.created`.
+    pub fn name(&self) -> &'static str {
+        return match self {
+            SessionEvent::Created => "session.created",
+            SessionEvent::Renewed => "session.renewed",
+            SessionEvent::Destroyed => "session.destroyed",
+            SessionEvent::Evicted => "session.evicted",
+        };
+    }
+}
+
+/// Observer of sessions lifecycle, e.g. for audit logging, cleaning data related to a session
+/// or collecting metrics.
+///
+/// Hooks are only given the hashed session id, and are called once the sessions lock has been
+/// released, so they may call back into `Sessions`.
+pub trait SessionHook: Send + Sync {
+    fn on_event(&self, hashed_id: &HashedId, event: SessionEvent);
+}
+
+impl<F> SessionHook for F
+where F: Fn(&HashedId, SessionEvent) + Send + Sync
+{
+    fn on_event(&self, hashed_id: &HashedId, event: SessionEvent) {
+        self(hashed_id, event);
+    }
+}
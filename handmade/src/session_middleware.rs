@@ -1,17 +1,76 @@
 use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Instant;
 
 use actix_web::{
-    cookie::{time::Duration, Cookie}, dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform}, Error, HttpMessage
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform}, Error, HttpMessage
 };
 use futures_util::future::LocalBoxFuture;
+use tracing::{field, info_span, Instrument, Span};
 
-use crate::sessions::{self, SESSION_COOKIE};
+use crate::flash_delivery::{self, FlashClient};
+use crate::metrics::METRICS;
+use crate::rate_limit::{client_key, SessionRateLimiter};
+use crate::session_hash::HashedId;
+use crate::session_transport::{CookieTransport, SessionTransport};
+use crate::sessions::{self, FLASH_KEY};
 
 // There are two steps in middleware processing.
 // 1. Middleware initialization, middleware factory gets called with
 //    next service in chain as parameter.
 // 2. Middleware's call method gets called with normal request.
-pub struct CheckSession;
+//
+// A session creation limiter can be set, in which case clients over the limit are refused new
+// sessions, and get session-less responses.
+//
+// The session id is read through the transports in the order they've been added, the first one
+// finding an id winning. The new id is sent back through that same transport, or through the
+// first one for new sessions. Without any transport, the id travels in a cookie.
+//
+// Every request runs in a `request` span carrying the hashed id of its session, if it has one
+// or once a handler creates it, so everything logged while handling it can be told apart.
+//
+// Clients that don't render pages (htmx, or asking for JSON) are handed the flash the handler has
+// taken from the store but not read, in the response headers.
+#[derive(Default)]
+pub struct CheckSession {
+    transports: Vec<Rc<dyn SessionTransport>>,
+    creation_limiter: Option<Arc<dyn SessionRateLimiter>>,
+    trust_forwarded_for: bool,
+    keep_unread: bool,
+}
+
+impl CheckSession {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    pub fn transport(mut self, transport: impl SessionTransport + 'static) -> Self {
+        self.transports.push(Rc::new(transport));
+        return self;
+    }
+
+    /// The limiter must be shared by every worker's middleware for the limit to hold across them.
+    pub fn limit_creation(mut self, limiter: Arc<dyn SessionRateLimiter>) -> Self {
+        self.creation_limiter = Some(limiter);
+        return self;
+    }
+
+    /// Identifies clients by their `Forwarded`/`X-Forwarded-For` address rather than the peer
+    /// one. Only enable it behind a proxy that overwrites these headers.
+    pub fn trust_forwarded_for(mut self, trust: bool) -> Self {
+        self.trust_forwarded_for = trust;
+        return self;
+    }
+
+    /// Values a handler took from the store but never read are logged and counted as dropped.
+    /// This gives them one more request to be read on instead.
+    pub fn keep_unread(mut self, keep: bool) -> Self {
+        self.keep_unread = keep;
+        return self;
+    }
+}
 
 // Middleware factory is `Transform` trait
 // `S` - type of the next service
@@ -29,12 +88,27 @@ where
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
-        ready(Ok(CheckSessionMiddleware { service }))
+        let transports = match self.transports.is_empty() {
+            true => vec![Rc::new(CookieTransport) as Rc<dyn SessionTransport>],
+            false => self.transports.clone(),
+        };
+
+        ready(Ok(CheckSessionMiddleware {
+            service,
+            transports,
+            creation_limiter: self.creation_limiter.clone(),
+            trust_forwarded_for: self.trust_forwarded_for,
+            keep_unread: self.keep_unread,
+        }))
     }
 }
 
 pub struct CheckSessionMiddleware<S> {
     service: S,
+    transports: Vec<Rc<dyn SessionTransport>>,
+    creation_limiter: Option<Arc<dyn SessionRateLimiter>>,
+    trust_forwarded_for: bool,
+    keep_unread: bool,
 }
 
 impl<S, B> Service<ServiceRequest> for CheckSessionMiddleware<S>
@@ -50,38 +124,152 @@ where
     forward_ready!(service);
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
-        let session_id_cookie = req.cookie(SESSION_COOKIE);
-        
-        let session_id = match session_id_cookie {
-            Some(cookie) => cookie.value().to_string(),
-            None => sessions::Sessions::new_session(),
-        };
-        
-        req.extensions_mut().insert(sessions::Sessions::get(&session_id));
+        let (transport, session_id) = self.transports
+            .iter()
+            .find_map(|transport| Some((transport, transport.read(&req)?)))
+            .map(|(transport, session_id)| (Rc::clone(transport), Some(session_id)))
+            .unwrap_or_else(|| (Rc::clone(&self.transports[0]), None));
+
+        let span = info_span!(
+            "request",
+            method = %req.method(),
+            path = %req.path(),
+            session = field::Empty,
+        );
+
+        if let Some(session_id) = &session_id {
+            span.record("session", field::display(HashedId::new(session_id)));
+        }
+
+        let mut session = sessions::Session::lazy(session_id.as_deref())
+            .ambient(session_id.is_some() && transport.is_ambient());
+        if let Some(limiter) = &self.creation_limiter {
+            let client = client_key(&req, self.trust_forwarded_for);
+            session = session.limit_creation(Arc::clone(limiter), client);
+        }
+
+        req.extensions_mut().insert(session.clone());
 
-        let fut: <S as Service<ServiceRequest>>::Future = self.service.call(req);
+        let client = FlashClient::of(req.headers());
+        let fut: <S as Service<ServiceRequest>>::Future = span.in_scope(|| self.service.call(req));
+        let keep_unread = self.keep_unread;
+
+        let started_at = Instant::now();
 
         Box::pin(async move {
-            let mut res: ServiceResponse<B> = fut.await?;
-            let response = res.response_mut();
-            
-            let cookie = Cookie::build(
-                sessions::SESSION_COOKIE,
-                &session_id
-            )
-                .same_site(actix_web::cookie::SameSite::Strict)
-                .path("/")
-                .secure(false)
-                .http_only(true)
-                .max_age(Duration::days(1))
-                .finish();
-
-            let cookie_has_been_set = response.add_cookie(&cookie);
-            if cookie_has_been_set.is_err() {
-                println!("{}", cookie_has_been_set.unwrap_err());
-            }
-            
-            return Ok(res);
-        })
+            let res = match fut.await {
+                Ok(res) => Ok(finish(res, &session, &*transport, client, keep_unread)),
+                // Whatever the handler took from the store is lost all the same.
+                Err(err) => {
+                    report_unread(&session, keep_unread);
+                    Err(err)
+                },
+            };
+
+            match &res {
+                Ok(res) => tracing::info!(
+                    event = "request.done",
+                    status = res.status().as_u16(),
+                    elapsed_ms = started_at.elapsed().as_millis() as u64,
+                ),
+                Err(err) => tracing::warn!(event = "request.failed", error = %err),
+            };
+
+            return res;
+        }.instrument(span))
+    }
+}
+
+/// Hands the flash over to `client`, reports the values the handler left unread, and sends the
+/// session id back through `transport` (or clears it, if the session has been invalidated).
+fn finish<B>(
+    mut res: ServiceResponse<B>,
+    session: &sessions::Session,
+    transport: &dyn SessionTransport,
+    client: Option<FlashClient>,
+    keep_unread: bool,
+) -> ServiceResponse<B> {
+    let headers = res.response_mut().headers_mut();
+
+    if let Some(client) = client {
+        flash_delivery::deliver(session, client, headers);
     }
+
+    report_unread(session, keep_unread);
+
+    if session.is_invalidated() {
+        if let Err(err) = transport.clear(headers) {
+            tracing::error!(event = "transport.clear_failed", error = %err);
+        }
+
+        return res;
+    }
+
+    // The handler may have created or regenerated the session. Read-only requests without a
+    // session don't get one.
+    let Some(session_id) = session.id() else {
+        return res;
+    };
+
+    if let Err(err) = transport.write(headers, &session_id) {
+        tracing::error!(event = "transport.write_failed", error = %err);
+    }
+
+    return res;
+}
+
+/// Logs and counts the values the handler left unread, keeping the ones that haven't been kept
+/// yet if asked to, and the flash it has read.
+fn report_unread(session: &sessions::Session, keep_unread: bool) {
+    if session.is_read(FLASH_KEY) {
+        METRICS.flash_consumed.inc();
+        tracing::info!(event = "flash.consumed");
+    }
+
+    let mut unread = session.unread();
+    if unread.is_empty() {
+        return;
+    }
+
+    let mut kept = Vec::new();
+    if keep_unread && !session.is_invalidated() {
+        let keep = unread
+            .iter()
+            .filter(|(key, _)| !session.was_kept(key))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        kept = session.keep_unread(keep).unwrap_or_else(|err| {
+            tracing::warn!(event = "session.keep_failed", error = %err);
+            Vec::new()
+        });
+    }
+
+    unread.retain(|(key, _)| !kept.contains(key));
+    for key in &kept {
+        METRICS.unread_kept.inc();
+        if key.as_ref() == FLASH_KEY {
+            METRICS.flash_kept.inc();
+        }
+
+        tracing::info!(event = "flash.kept", key = key.as_ref());
+    }
+
+    for (key, value) in &unread {
+        METRICS.unread_dropped.inc();
+        if key.as_ref() == FLASH_KEY {
+            METRICS.flash_dropped.inc();
+        }
+
+        tracing::warn!(
+            event = "flash.dropped",
+            key = key.as_ref(),
+            bytes = value.to_string().len(),
+        );
+    }
+}
+
+/// Tags the current request span with the session it's using.
+pub fn record_session(hashed_id: &HashedId) {
+    Span::current().record("session", field::display(hashed_id));
 }
@@ -0,0 +1,107 @@
+use actix_web::cookie::{time::Duration, Cookie, SameSite};
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue, InvalidHeaderValue, AUTHORIZATION, SET_COOKIE};
+
+use crate::sessions::SESSION_COOKIE;
+
+pub const SESSION_ID_HEADER: HeaderName = HeaderName::from_static("x-session-id");
+
+/// How the session id travels between the client and the server.
+pub trait SessionTransport {
+    /// Reads the session id the client has sent, if any.
+    fn read(&self, req: &ServiceRequest) -> Option<String>;
+
+    /// Sends the session id back to the client.
+    fn write(&self, headers: &mut HeaderMap, session_id: &str) -> Result<(), InvalidHeaderValue>;
+
+    /// Tells the client to forget its session id.
+    fn clear(&self, headers: &mut HeaderMap) -> Result<(), InvalidHeaderValue>;
+
+    /// Whether browsers send the id on their own, even along requests other sites make them send.
+    fn is_ambient(&self) -> bool {
+        return false;
+    }
+}
+
+/// Browsers: the id is held in the `SESSION_COOKIE` cookie.
+pub struct CookieTransport;
+
+impl SessionTransport for CookieTransport {
+    fn read(&self, req: &ServiceRequest) -> Option<String> {
+        return req.cookie(SESSION_COOKIE).map(|cookie| cookie.value().to_string());
+    }
+
+    fn write(&self, headers: &mut HeaderMap, session_id: &str) -> Result<(), InvalidHeaderValue> {
+        let cookie = Cookie::build(SESSION_COOKIE, session_id)
+            .same_site(SameSite::Strict)
+            .path("/")
+            .secure(false)
+            .http_only(true)
+            .max_age(Duration::days(1))
+            .finish();
+
+        headers.append(SET_COOKIE, HeaderValue::from_str(&cookie.to_string())?);
+        return Ok(());
+    }
+
+    fn clear(&self, headers: &mut HeaderMap) -> Result<(), InvalidHeaderValue> {
+        let mut cookie = Cookie::build(SESSION_COOKIE, "").path("/").finish();
+        cookie.make_removal();
+
+        headers.append(SET_COOKIE, HeaderValue::from_str(&cookie.to_string())?);
+        return Ok(());
+    }
+
+    fn is_ambient(&self) -> bool {
+        return true;
+    }
+}
+
+/// API clients: the id is sent in the `X-Session-Id` header, both ways. It's sent back empty
+/// once the session has been invalidated.
+pub struct HeaderTransport;
+
+impl SessionTransport for HeaderTransport {
+    fn read(&self, req: &ServiceRequest) -> Option<String> {
+        return req.headers()
+            .get(SESSION_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| !value.is_empty())
+            .map(str::to_string);
+    }
+
+    fn write(&self, headers: &mut HeaderMap, session_id: &str) -> Result<(), InvalidHeaderValue> {
+        headers.insert(SESSION_ID_HEADER, HeaderValue::from_str(session_id)?);
+        return Ok(());
+    }
+
+    fn clear(&self, headers: &mut HeaderMap) -> Result<(), InvalidHeaderValue> {
+        headers.insert(SESSION_ID_HEADER, HeaderValue::from_static(""));
+        return Ok(());
+    }
+}
+
+/// CLI tools and the like: the id is sent as an `Authorization: Bearer` token. Responses can't
+/// carry an `Authorization` header, so the id is sent back in `X-Session-Id`, as with
+/// `HeaderTransport`.
+pub struct BearerTransport;
+
+impl SessionTransport for BearerTransport {
+    fn read(&self, req: &ServiceRequest) -> Option<String> {
+        return req.headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::trim)
+            .filter(|token| !token.is_empty())
+            .map(str::to_string);
+    }
+
+    fn write(&self, headers: &mut HeaderMap, session_id: &str) -> Result<(), InvalidHeaderValue> {
+        return HeaderTransport.write(headers, session_id);
+    }
+
+    fn clear(&self, headers: &mut HeaderMap) -> Result<(), InvalidHeaderValue> {
+        return HeaderTransport.clear(headers);
+    }
+}
@@ -1,83 +1,1043 @@
-use std::collections::HashMap;
-use std::sync::{Arc, LazyLock, RwLock, RwLockReadGuard};
+use std::cell::{RefCell, RefMut};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::{Debug, Display};
+use std::rc::Rc;
+use std::sync::{Arc, LazyLock, Mutex, RwLock, RwLockReadGuard};
+use std::time::{Duration, SystemTime};
 
+use serde::Serialize;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
 use uuid::Uuid;
 
+use crate::metrics::METRICS;
+use crate::rate_limit::SessionRateLimiter;
+use crate::session_hash::HashedId;
+use crate::session_hooks::{SessionEvent, SessionHook};
+use crate::session_middleware::record_session;
+
 type SessionMap = HashMap<Box<str>, serde_json::Value>;
 
-#[derive(Debug, Clone)]
-pub struct Session {
-    id: Box<str>,
-    pub map: Option<SessionMap>,
+/// Reserved entry listing the keys a previous request left unread and that have been kept for
+/// one more request, so they aren't kept twice.
+const KEPT_KEY: &str = "_kept_unread";
+/// Holds the flash message of the previous request.
+pub const FLASH_KEY: &str = "flash";
+/// Holds the flash messages enqueued by `Sessions::enqueue_flash`, as a list.
+pub const FLASH_QUEUE_KEY: &str = "flash_queue";
+
+/// What a `Session` has taken from the store once a handler has read it.
+struct Loaded {
+    version: u64,
+    map: Option<SessionMap>,
+    /// The keys handlers have read.
+    read: HashSet<Box<str>>,
+    /// The keys that have already been kept once for being left unread.
+    kept: Vec<Box<str>>,
 }
 
+struct SessionInner {
+    /// `None` until the session has been created, for requests that came without a cookie.
+    id: Option<Box<str>>,
+    invalidated: bool,
+    loaded: Option<Loaded>,
+    /// The limiter new sessions are checked against, along with the client asking for one.
+    creation_limit: Option<(Arc<dyn SessionRateLimiter>, String)>,
+    /// Whether the id came through a transport browsers send on their own (see
+    /// `SessionTransport::is_ambient`).
+    ambient: bool,
+}
+
+/// The session of the current request. It is lazy: the stored map is only taken out of the store
+/// when a handler first reads it, and a request without a cookie only gets a session (and a
+/// cookie) once a handler writes to it.
+///
+/// Its clones share the same state, so the middleware sees whether a handler has created,
+/// regenerated or invalidated it.
+#[derive(Clone)]
+pub struct Session(Rc<RefCell<SessionInner>>);
+
 impl Session {
-    pub fn id(&self) -> &Box<str> {
-        return &self.id;
+    pub fn lazy(session_id: Option<&str>) -> Self {
+        return Session(Rc::new(RefCell::new(SessionInner {
+            id: session_id.map(Into::into),
+            invalidated: false,
+            loaded: None,
+            creation_limit: None,
+            ambient: false,
+        })));
+    }
+
+    /// Marks the id as read through a transport browsers send on their own, e.g. a cookie.
+    pub fn ambient(self, ambient: bool) -> Self {
+        self.0.borrow_mut().ambient = ambient;
+        return self;
+    }
+
+    /// Whether the id came through a transport browsers send on their own, in which case the
+    /// request may have been forged by another site. Handlers with side effects beyond the
+    /// session should refuse such requests.
+    pub fn is_ambient(&self) -> bool {
+        return self.0.borrow().ambient;
+    }
+
+    /// Checks every session this one creates against `limiter`, on behalf of `client`.
+    pub fn limit_creation(self, limiter: Arc<dyn SessionRateLimiter>, client: String) -> Self {
+        self.0.borrow_mut().creation_limit = Some((limiter, client));
+        return self;
+    }
+
+    pub fn id(&self) -> Option<String> {
+        return self.0.borrow().id.as_deref().map(str::to_string);
+    }
+
+    /// Returns the session id, creating the session if there's none yet. Ids the store doesn't
+    /// know (expired, evicted or made up by the client) are replaced rather than adopted, so they
+    /// can't be used to create sessions past the limiter.
+    fn id_or_create(&self) -> Result<String, SessionsError> {
+        if let Some(session_id) = self.id() {
+            if Sessions::all().get(&HashedId::new(&session_id)).is_some() {
+                return Ok(session_id);
+            }
+        }
+
+        if let Some((limiter, client)) = &self.0.borrow().creation_limit {
+            if !limiter.try_acquire(client) {
+                tracing::warn!(event = "session.rate_limited", client);
+                return Err(SessionsError::RateLimited);
+            }
+        }
+
+        let session_id = Sessions::new_session();
+        record_session(&HashedId::new(&session_id));
+        self.0.borrow_mut().id = Some(session_id.clone().into_boxed_str());
+        return Ok(session_id);
+    }
+
+    fn load(&self) -> RefMut<'_, Loaded> {
+        let mut inner = self.0.borrow_mut();
+        if inner.loaded.is_none() {
+            let (version, mut map) = match &inner.id {
+                Some(session_id) => Sessions::take(session_id),
+                None => (0, None),
+            };
+
+            let kept = map
+                .as_mut()
+                .and_then(|map| map.remove(KEPT_KEY))
+                .and_then(|kept| serde_json::from_value(kept).ok())
+                .unwrap_or_default();
+
+            inner.loaded = Some(Loaded { version, map, read: HashSet::new(), kept });
+        }
+
+        return RefMut::map(inner, |inner| inner.loaded.as_mut().unwrap());
+    }
+
+    /// Reads a value stored by the previous request.
+    pub fn get(&self, key: &str) -> Option<serde_json::Value> {
+        let mut loaded = self.load();
+        loaded.read.insert(key.into());
+        return loaded.map.as_ref()?.get(key).cloned();
+    }
+
+    /// The values taken from the store that no handler has read, which are lost once the request
+    /// is over.
+    pub fn unread(&self) -> Vec<(Box<str>, serde_json::Value)> {
+        let inner = self.0.borrow();
+        let Some(Loaded { map: Some(map), read, .. }) = &inner.loaded else {
+            return Vec::new();
+        };
+
+        return map
+            .iter()
+            .filter(|(key, _)| !read.contains(*key))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+    }
+
+    /// Whether a value under `key` has been taken from the store and read.
+    pub fn is_read(&self, key: &str) -> bool {
+        let inner = self.0.borrow();
+        let Some(Loaded { map: Some(map), read, .. }) = &inner.loaded else {
+            return false;
+        };
+
+        return map.contains_key(key) && read.contains(key);
+    }
+
+    /// Reads a value taken from the store, unless a handler has read it already. Never takes the
+    /// session out of the store.
+    pub fn get_unread(&self, key: &str) -> Option<serde_json::Value> {
+        let mut inner = self.0.borrow_mut();
+        let loaded = inner.loaded.as_mut()?;
+        if !loaded.read.insert(key.into()) {
+            return None;
+        }
+
+        return loaded.map.as_ref()?.get(key).cloned();
+    }
+
+    /// Takes the flash and enqueued flash messages the handler hasn't read, to hand them over
+    /// alongside the response. When the handler hasn't taken the session out of the store, only
+    /// they are taken from it, and the other values are left for the next request.
+    pub fn take_flash(&self) -> SessionMap {
+        let keys = [FLASH_KEY, FLASH_QUEUE_KEY];
+        if self.0.borrow().loaded.is_some() {
+            return keys
+                .into_iter()
+                .filter_map(|key| Some((key.into(), self.get_unread(key)?)))
+                .collect();
+        }
+
+        let Some(session_id) = self.id() else {
+            return SessionMap::new();
+        };
+
+        let taken = Sessions::take_values(&session_id, &keys).unwrap_or_else(|err| {
+            tracing::warn!(event = "session.take_failed", error = %err);
+            SessionMap::new()
+        });
+
+        if taken.contains_key(FLASH_KEY) {
+            METRICS.flash_consumed.inc();
+            tracing::info!(event = "flash.consumed");
+        }
+
+        return taken;
+    }
+
+    /// Whether the value under `key` has already been kept once for being left unread.
+    pub fn was_kept(&self, key: &str) -> bool {
+        return self.0
+            .borrow()
+            .loaded
+            .as_ref()
+            .is_some_and(|loaded| loaded.kept.iter().any(|kept| kept.as_ref() == key));
+    }
+
+    /// Puts values no handler has read back in the store, for the next request to get. Keys the
+    /// handler has written to in the meantime are left alone. Returns the keys kept.
+    pub fn keep_unread(
+        &self,
+        entries: Vec<(Box<str>, serde_json::Value)>,
+    ) -> Result<Vec<Box<str>>, SessionsError> {
+        let Some(session_id) = self.id() else {
+            return Ok(Vec::new());
+        };
+
+        return Sessions::keep(&session_id, entries);
+    }
+
+    /// Stores a value for the next request.
+    pub fn insert(&self, key: &str, value: serde_json::Value) -> Result<(), SessionsError> {
+        Sessions::store(&self.id_or_create()?, key, value)?;
+        if key == FLASH_KEY {
+            METRICS.flash_set.inc();
+            tracing::info!(event = "flash.set");
+        }
+
+        return Ok(());
+    }
+
+    /// Moves the stored session to a brand new id (e.g. at login, against session fixation). The
+    /// old id stops working at once, and the response cookie is updated with the new one. Ids the
+    /// store doesn't know are left alone, as creating a session for them would skip the limiter.
+    pub fn regenerate(&self) {
+        let Some(new_id) = self.id().and_then(|session_id| Sessions::regenerate(&session_id)) else {
+            return;
+        };
+
+        record_session(&HashedId::new(&new_id));
+        self.0.borrow_mut().id = Some(new_id.into_boxed_str());
+    }
+
+    /// Destroys the stored session, and clears the response cookie.
+    pub fn invalidate(&self) {
+        if let Some(session_id) = self.id() {
+            Sessions::clean(&session_id);
+        }
+
+        self.0.borrow_mut().invalidated = true;
+    }
+
+    pub fn is_invalidated(&self) -> bool {
+        return self.0.borrow().invalidated;
     }
 }
 
-static SESSIONS: LazyLock<Arc<RwLock<HashMap<Box<str>, SessionMap>>>> = LazyLock::new(|| {
-    Arc::new(RwLock::new(HashMap::new()))
+/// Only shows the hashed id, so the session can be logged without leaking it.
+impl Debug for Session {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let inner = self.0.borrow();
+        f.debug_struct("Session")
+            .field("id", &inner.id.as_deref().map(HashedId::new))
+            .field("invalidated", &inner.invalidated)
+            .field("map", &inner.loaded.as_ref().map(|loaded| &loaded.map))
+            .finish()
+    }
+}
+
+/// A stored session map. `version` is bumped on every write, so a writer can tell whether
+/// someone else has written to the session since it has been read.
+#[derive(Debug)]
+pub struct VersionedSession {
+    pub version: u64,
+    pub map: SessionMap,
+    bytes: usize,
+    last_access: u64,
+    created_at: SystemTime,
+}
+
+impl VersionedSession {
+    /// Approximate size of the session map, in bytes.
+    pub fn bytes(&self) -> usize {
+        return self.bytes;
+    }
+
+    /// How long ago the session has been created. Sessions don't expire in the store: they're
+    /// only ever evicted past the `SessionsLimits`.
+    pub fn age(&self) -> Duration {
+        return self.created_at.elapsed().unwrap_or_default();
+    }
+}
+
+#[derive(Debug)]
+pub enum SessionsError {
+    /// The stored session has been written to since the expected version.
+    VersionConflict { expected: u64, found: u64 },
+    /// The session map would be larger than `SessionsLimits::max_session_bytes`.
+    TooLarge { size: usize, limit: usize },
+    /// The client has been refused a new session by the creation limiter.
+    RateLimited,
+}
+
+impl Display for SessionsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::VersionConflict { expected, found } => {
+                write!(f, "Expected session version {}, found {}.", expected, found)
+            },
+            Self::TooLarge { size, limit } => {
+                write!(f, "Session would take {} bytes, but the limit is {}.", size, limit)
+            },
+            Self::RateLimited => write!(f, "Too many sessions have been created for this client."),
+        }
+    }
+}
+
+/// Caps on the sessions store. Once `max_sessions` or `max_total_bytes` is exceeded, the least
+/// recently used sessions are evicted. Sessions larger than `max_session_bytes` are refused.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SessionsLimits {
+    pub max_sessions: Option<usize>,
+    pub max_total_bytes: Option<usize>,
+    pub max_session_bytes: Option<usize>,
+}
+
+/// What a write did to the store, so hooks can be notified once its lock is released.
+#[derive(Default)]
+struct Outcome {
+    created: bool,
+    /// An existing session has been written to.
+    updated: bool,
+    evicted: Vec<HashedId>,
+    version: u64,
+}
+
+/// Sessions indexed by the keyed hash of their id, never by the id itself.
+#[derive(Default)]
+pub struct SessionsStore {
+    sessions: HashMap<HashedId, VersionedSession>,
+    /// Hashed session ids by last access, least recently used first.
+    lru: BTreeMap<u64, HashedId>,
+    tick: u64,
+    bytes: usize,
+    limits: SessionsLimits,
+}
+
+impl SessionsStore {
+    pub fn iter(&self) -> impl Iterator<Item = (&HashedId, &VersionedSession)> {
+        return self.sessions.iter();
+    }
+
+    pub fn get(&self, hashed_id: &HashedId) -> Option<&VersionedSession> {
+        return self.sessions.get(hashed_id);
+    }
+
+    pub fn len(&self) -> usize {
+        return self.sessions.len();
+    }
+
+    /// Approximate size of all stored sessions, in bytes.
+    pub fn bytes(&self) -> usize {
+        return self.bytes;
+    }
+
+    fn check_size(&self, map: &SessionMap) -> Result<usize, SessionsError> {
+        let size = map
+            .iter()
+            .map(|(key, value)| key.len() + value.to_string().len())
+            .sum();
+
+        return match self.limits.max_session_bytes {
+            Some(limit) if size > limit => Err(SessionsError::TooLarge { size, limit }),
+            _ => Ok(size),
+        };
+    }
+
+    /// Replaces the session map (creating the session if needed), marks it as the most recently
+    /// used one and evicts others if the store has grown past its limits.
+    fn write(&mut self, hashed_id: &HashedId, map: SessionMap) -> Result<Outcome, SessionsError> {
+        let size = self.check_size(&map)?;
+        self.tick += 1;
+
+        let mut outcome = Outcome::default();
+        match self.sessions.get_mut(hashed_id) {
+            Some(session) => {
+                self.lru.remove(&session.last_access);
+                self.bytes -= session.bytes;
+                session.map = map;
+                session.bytes = size;
+                session.version += 1;
+                session.last_access = self.tick;
+                outcome.version = session.version;
+                outcome.updated = true;
+            },
+            None => {
+                self.sessions.insert(hashed_id.clone(), VersionedSession {
+                    version: 0,
+                    map,
+                    bytes: size,
+                    last_access: self.tick,
+                    created_at: SystemTime::now(),
+                });
+                outcome.created = true;
+            },
+        };
+
+        self.lru.insert(self.tick, hashed_id.clone());
+        self.bytes += size;
+        outcome.evicted = self.evict(hashed_id);
+
+        return Ok(outcome);
+    }
+
+    /// Takes the session map out, leaving an empty one (with a bumped version) in its place. Reading
+    /// a session that isn't stored doesn't create it.
+    fn take(&mut self, hashed_id: &HashedId) -> (Option<SessionMap>, Outcome) {
+        let Some(session) = self.sessions.get_mut(hashed_id) else {
+            return (None, Outcome::default());
+        };
+
+        let map = Some(std::mem::take(&mut session.map));
+        let mut outcome = self
+            .write(hashed_id, HashMap::new())
+            .expect("an empty session always fits");
+
+        // Taking is a read, even though it empties the stored map.
+        outcome.updated = false;
+
+        return (map, outcome);
+    }
+
+    /// Moves a session to another id, keeping its data, version and place in the LRU.
+    fn rename(&mut self, hashed_id: &HashedId, new_hashed_id: HashedId) -> Option<()> {
+        let session = self.sessions.remove(hashed_id)?;
+        self.lru.insert(session.last_access, new_hashed_id.clone());
+        self.sessions.insert(new_hashed_id, session);
+        return Some(());
+    }
+
+    fn remove(&mut self, hashed_id: &HashedId) -> Option<VersionedSession> {
+        let session = self.sessions.remove(hashed_id)?;
+        self.lru.remove(&session.last_access);
+        self.bytes -= session.bytes;
+        return Some(session);
+    }
+
+    fn over_capacity(&self) -> bool {
+        return self.limits.max_sessions.is_some_and(|max| self.sessions.len() > max)
+            || self.limits.max_total_bytes.is_some_and(|max| self.bytes > max);
+    }
+
+    /// Evicts the least recently used sessions until the store fits its limits again. The
+    /// session being written (`keep`) is never evicted.
+    fn evict(&mut self, keep: &HashedId) -> Vec<HashedId> {
+        let mut evicted = Vec::new();
+        while self.over_capacity() {
+            let Some(hashed_id) = self.lru.values().find(|id| *id != keep).cloned() else {
+                break;
+            };
+
+            self.remove(&hashed_id);
+            evicted.push(hashed_id);
+        }
+
+        return evicted;
+    }
+}
+
+/// Merges the values a parallel request has `stored` over the `forwarded` ones. Its values win,
+/// except for flash messages: a forwarded flash it has replaced goes to the flash queue, and both
+/// queues are kept, forwarded messages first.
+fn merge_forwarded(hashed_id: &HashedId, forwarded: &mut SessionMap, stored: SessionMap) {
+    for (key, value) in stored {
+        let Some(replaced) = forwarded.insert(key.clone(), value.clone()) else {
+            continue;
+        };
+
+        if replaced == value {
+            continue;
+        }
+
+        match key.as_ref() {
+            FLASH_KEY => {
+                let queue = forwarded
+                    .entry(FLASH_QUEUE_KEY.into())
+                    .or_insert_with(|| serde_json::Value::Array(Vec::new()));
+
+                match queue {
+                    serde_json::Value::Array(queue) => queue.insert(0, replaced),
+                    _ => *queue = serde_json::json!([replaced]),
+                };
+            },
+            FLASH_QUEUE_KEY => {
+                let queue = [replaced, value]
+                    .into_iter()
+                    .flat_map(|queue| match queue {
+                        serde_json::Value::Array(queue) => queue,
+                        message => vec![message],
+                    })
+                    .collect();
+
+                forwarded.insert(key, serde_json::Value::Array(queue));
+            },
+            _ => {
+                METRICS.forward_discarded.inc();
+                tracing::warn!(event = "session.forward_discarded", session = %hashed_id, key = key.as_ref());
+            },
+        };
+    }
+}
+
+static SESSIONS: LazyLock<Arc<RwLock<SessionsStore>>> = LazyLock::new(|| {
+    Arc::new(RwLock::new(SessionsStore::default()))
 });
 
-pub const SESSION_COOKIE: &'static str = "_SESSION_ID";
+static HOOKS: LazyLock<RwLock<Vec<Arc<dyn SessionHook>>>> = LazyLock::new(|| {
+    RwLock::new(Vec::new())
+});
+
+/// How many pushed messages a watcher may lag behind before it's dropped.
+const WATCHER_CAPACITY: usize = 16;
+
+/// The open subscriptions to the flash messages pushed to each session.
+static WATCHERS: LazyLock<Mutex<HashMap<HashedId, Vec<Sender<serde_json::Value>>>>> =
+    LazyLock::new(Mutex::default);
+
+pub const SESSION_COOKIE: &str = "_SESSION_ID";
 
 pub struct Sessions;
 
 impl Sessions {
-    pub fn all<'a>() -> RwLockReadGuard<'a, HashMap<Box<str>, SessionMap>> {
+    pub fn all<'a>() -> RwLockReadGuard<'a, SessionsStore> {
         return SESSIONS.read().unwrap();
     }
 
-    pub fn put(session_id: Box<str>, value: SessionMap) -> () {
+    /// Sets the store limits. They're enforced from the next write on.
+    pub fn set_limits(limits: SessionsLimits) {
+        SESSIONS.write().unwrap().limits = limits;
+    }
+
+    /// Registers a hook to be notified of every session lifecycle event. Hooks are called once
+    /// the sessions lock has been released, so they may call back into `Sessions`.
+    pub fn register_hook(hook: impl SessionHook + 'static) {
+        HOOKS.write().unwrap().push(Arc::new(hook));
+    }
+
+    fn emit(hashed_id: &HashedId, event: SessionEvent) {
+        METRICS.record(event);
+        tracing::info!(event = event.name(), session = %hashed_id);
+
+        // Closes the watchers of a session that is gone.
+        if matches!(event, SessionEvent::Destroyed | SessionEvent::Evicted) {
+            WATCHERS.lock().unwrap().remove(hashed_id);
+        }
+        let hooks = HOOKS.read().unwrap().clone();
+        for hook in hooks {
+            hook.on_event(hashed_id, event);
+        }
+    }
+
+    fn notify(hashed_id: &HashedId, outcome: &Outcome) {
+        if outcome.updated {
+            METRICS.updates.inc();
+            tracing::debug!(event = "session.update", session = %hashed_id, version = outcome.version);
+        }
+
+        if outcome.created {
+            Self::emit(hashed_id, SessionEvent::Created);
+        }
+
+        for evicted_id in &outcome.evicted {
+            Self::emit(evicted_id, SessionEvent::Evicted);
+        }
+    }
+
+    /// Replaces the whole session map only if it is still at the `expected` version (a session
+    /// that isn't stored is at version 0). Returns the new version, or
+    /// `SessionsError::VersionConflict` if someone else has written to it in the meantime.
+    pub fn compare_and_put(
+        session_id: &str,
+        expected: u64,
+        value: SessionMap,
+    ) -> Result<u64, SessionsError> {
+        let hashed_id = HashedId::new(session_id);
         let mut sessions = SESSIONS.write().unwrap();
-        match sessions.get_mut(&session_id) {
-            Some(hashmap) => *hashmap = value,
-            None => { sessions.insert(session_id, value); },
-        };
+        let found = sessions.get(&hashed_id).map_or(0, |session| session.version);
+        if found != expected {
+            return Err(SessionsError::VersionConflict { expected, found });
+        }
+
+        let outcome = sessions.write(&hashed_id, value)?;
+        drop(sessions);
+
+        Self::notify(&hashed_id, &outcome);
+        return Ok(outcome.version);
     }
 
-    pub fn store(session_id: &str, key: &str, value: serde_json::Value) -> () {
+    /// Stores the entries whose keys the session doesn't hold, remembering them as kept. Returns
+    /// the keys stored.
+    pub fn keep(
+        session_id: &str,
+        entries: Vec<(Box<str>, serde_json::Value)>,
+    ) -> Result<Vec<Box<str>>, SessionsError> {
+        let hashed_id = HashedId::new(session_id);
         let mut sessions = SESSIONS.write().unwrap();
+        let Some(mut map) = sessions.get(&hashed_id).map(|session| session.map.clone()) else {
+            return Ok(Vec::new());
+        };
 
-        if !sessions.contains_key(session_id) {
-            sessions.insert(session_id.to_string().into_boxed_str(), HashMap::new());
+        let mut kept = Vec::new();
+        for (key, value) in entries {
+            match (map.get_mut(&key), value) {
+                (None, value) => {
+                    map.insert(key.clone(), value);
+                },
+                // Messages enqueued in the meantime go after the kept ones.
+                (Some(serde_json::Value::Array(queue)), serde_json::Value::Array(mut unread))
+                    if key.as_ref() == FLASH_QUEUE_KEY =>
+                {
+                    unread.append(queue);
+                    *queue = unread;
+                },
+                _ => continue,
+            };
+
+            kept.push(key);
         }
 
-        let session = sessions.get_mut(session_id).unwrap();
+        if kept.is_empty() {
+            return Ok(kept);
+        }
 
-        session.insert(key.to_string().into_boxed_str(), value);
+        map.insert(KEPT_KEY.into(), serde_json::json!(kept));
+        let outcome = sessions.write(&hashed_id, map)?;
+        drop(sessions);
+
+        Self::notify(&hashed_id, &outcome);
+        return Ok(kept);
     }
 
-    pub fn forward(session: Session) -> () {
-        return Self::put(session.id, session.map.unwrap_or(HashMap::new()));
+    /// Takes the values under `keys` out of the stored session, leaving the others in place.
+    pub fn take_values(session_id: &str, keys: &[&str]) -> Result<SessionMap, SessionsError> {
+        let hashed_id = HashedId::new(session_id);
+        let mut sessions = SESSIONS.write().unwrap();
+        let Some(mut map) = sessions.get(&hashed_id).map(|session| session.map.clone()) else {
+            return Ok(SessionMap::new());
+        };
+
+        let taken = keys
+            .iter()
+            .filter_map(|key| map.remove_entry(*key))
+            .collect::<SessionMap>();
+
+        if taken.is_empty() {
+            return Ok(taken);
+        }
+
+        // They're no longer kept either.
+        if let Some(serde_json::Value::Array(kept)) = map.get_mut(KEPT_KEY) {
+            kept.retain(|key| !key.as_str().is_some_and(|key| taken.contains_key(key)));
+        }
+
+        let outcome = sessions.write(&hashed_id, map)?;
+        drop(sessions);
+
+        Self::notify(&hashed_id, &outcome);
+        return Ok(taken);
     }
 
-    pub fn get(session_id: &str) -> Session {
+    pub fn store(session_id: &str, key: &str, value: serde_json::Value) -> Result<(), SessionsError> {
+        let hashed_id = HashedId::new(session_id);
         let mut sessions = SESSIONS.write().unwrap();
-        let session = sessions.remove(session_id);
-        sessions.insert(session_id.into(), HashMap::new());
 
-        return Session {
-            id: session_id.to_string().into_boxed_str(),
-            map: session,
+        let mut map = sessions
+            .get(&hashed_id)
+            .map(|session| session.map.clone())
+            .unwrap_or_default();
+
+        map.insert(key.to_string().into_boxed_str(), value);
+        let outcome = sessions.write(&hashed_id, map)?;
+        drop(sessions);
+
+        Self::notify(&hashed_id, &outcome);
+        return Ok(());
+    }
+
+    /// Makes the given session available to the next request.
+    ///
+    /// If a parallel request has written to the session since it was taken, the forwarded
+    /// values are merged key by key (see `merge_forwarded`): values written by the parallel
+    /// request win, and forwarded ones only fill the keys it hasn't set. A forwarded flash it has
+    /// replaced is enqueued after it rather than dropped; any other replaced value is logged as
+    /// `session.forward_discarded` and counted.
+    ///
+    /// If the write fails (e.g. the merged session is too large), the taken values are handed back
+    /// to the request, as if it hadn't forwarded them.
+    ///
+    /// A session no handler has read has nothing to forward: its values are still in the store.
+    pub fn forward(session: Session) -> Result<(), SessionsError> {
+        let Some(loaded) = session.0.borrow_mut().loaded.take() else {
+            return Ok(());
+        };
+
+        let Some(map) = &loaded.map else {
+            return Ok(());
+        };
+
+        let forwarded = session
+            .id_or_create()
+            .and_then(|session_id| Self::write_forwarded(&session_id, loaded.version, map.clone()));
+
+        if forwarded.is_err() {
+            session.0.borrow_mut().loaded = Some(loaded);
+        }
+
+        return forwarded;
+    }
+
+    /// Writes the forwarded `map` with `compare_and_put`, merging it over the stored session and
+    /// trying again whenever a parallel request has written to it first.
+    fn write_forwarded(session_id: &str, version: u64, map: SessionMap) -> Result<(), SessionsError> {
+        let hashed_id = HashedId::new(session_id);
+        let (mut expected, mut merged) = (version, map.clone());
+
+        loop {
+            match Self::compare_and_put(session_id, expected, merged) {
+                Err(SessionsError::VersionConflict { .. }) => {},
+                written => return written.map(|_| ()),
+            };
+
+            // Merged from the forwarded values every time, so nothing is merged twice.
+            merged = map.clone();
+            expected = match Self::all().get(&hashed_id) {
+                Some(stored) => {
+                    merge_forwarded(&hashed_id, &mut merged, stored.map.clone());
+                    stored.version
+                },
+                None => 0,
+            };
+        }
+    }
+
+    /// Takes the session map out of the store, leaving an empty one in its place. The swap happens
+    /// under a single lock, so among concurrent requests exactly one gets the stored values.
+    ///
+    /// Returns the version of the (now empty) stored session, along with the taken map.
+    pub fn take(session_id: &str) -> (u64, Option<SessionMap>) {
+        let hashed_id = HashedId::new(session_id);
+        let (map, outcome) = SESSIONS.write().unwrap().take(&hashed_id);
+        Self::notify(&hashed_id, &outcome);
+
+        let result = match map.is_some() {
+            true => {
+                METRICS.loads_hit.inc();
+                "hit"
+            },
+            false => {
+                METRICS.loads_miss.inc();
+                "miss"
+            },
         };
+
+        tracing::debug!(event = "session.load", session = %hashed_id, result, version = outcome.version);
+
+        return (outcome.version, map);
     }
 
-    pub fn store_new_session(value: SessionMap) -> String {
+    /// Moves the session stored under `session_id` to a new id, which is returned. Both happen under
+    /// a single lock, so no request can ever see the session under both ids. Returns `None` if the
+    /// session isn't stored.
+    pub fn regenerate(session_id: &str) -> Option<String> {
+        let new_id = Uuid::new_v4().to_string();
+        let new_hashed_id = HashedId::new(&new_id);
+
+        let hashed_id = HashedId::new(session_id);
+        SESSIONS.write().unwrap().rename(&hashed_id, new_hashed_id.clone())?;
+
+        let mut watchers = WATCHERS.lock().unwrap();
+        if let Some(senders) = watchers.remove(&hashed_id) {
+            watchers.insert(new_hashed_id.clone(), senders);
+        }
+        drop(watchers);
+
+        Self::emit(&new_hashed_id, SessionEvent::Renewed);
+        return Some(new_id);
+    }
+
+    pub fn store_new_session(value: SessionMap) -> Result<String, SessionsError> {
         let session_id = Uuid::new_v4().to_string();
-        let mut sessions = SESSIONS.write().unwrap();
-        sessions.insert(session_id.clone().into_boxed_str(), value);
+        let hashed_id = HashedId::new(&session_id);
+        let outcome = SESSIONS.write().unwrap().write(&hashed_id, value)?;
 
-        return session_id;
+        Self::notify(&hashed_id, &outcome);
+        return Ok(session_id);
     }
 
     pub fn new_session() -> String {
-        return Self::store_new_session(HashMap::new());
+        return Self::store_new_session(HashMap::new()).expect("an empty session always fits");
+    }
+
+    pub fn clean(session_id: &str) {
+        Self::revoke(&HashedId::new(session_id));
+    }
+
+    /// Subscribes to the flash messages pushed to a session, as `{"flash": ..}` payloads. The
+    /// receiver is closed once the session is gone, or once it lags `WATCHER_CAPACITY` messages
+    /// behind, and follows the session when it's regenerated.
+    pub fn watch(hashed_id: &HashedId) -> Receiver<serde_json::Value> {
+        let (sender, receiver) = channel(WATCHER_CAPACITY);
+        let mut watchers = WATCHERS.lock().unwrap();
+        let senders = watchers.entry(hashed_id.clone()).or_default();
+        // Drops the watchers that have gone away (e.g. pages that reconnected since), which a
+        // session nobody pushes to would otherwise keep forever.
+        senders.retain(|sender| !sender.is_closed());
+        senders.push(sender);
+        return receiver;
     }
 
-    pub fn clean(session_id: &str) -> () {
-        let _ = SESSIONS.write().unwrap().remove(session_id);
+    /// Pushes a flash message to the watchers of a session, straight away. Returns how many got
+    /// it.
+    pub fn push_flash(hashed_id: &HashedId, message: impl Serialize) -> usize {
+        let payload = serde_json::json!({"flash": message});
+        let mut watchers = WATCHERS.lock().unwrap();
+        let Some(senders) = watchers.get_mut(hashed_id) else {
+            return 0;
+        };
+
+        // Watchers too slow to keep up are dropped, which ends their stream.
+        senders.retain(|sender| match sender.try_send(payload.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                tracing::warn!(event = "flash.watcher_dropped", session = %hashed_id);
+                false
+            },
+            Err(TrySendError::Closed(_)) => false,
+        });
+        let delivered = senders.len();
+        if senders.is_empty() {
+            watchers.remove(hashed_id);
+        }
+
+        tracing::info!(event = "flash.pushed", session = %hashed_id, delivered);
+        return delivered;
+    }
+
+    /// Enqueues a flash message for the next request of a session, e.g. from a background job or
+    /// the admin, which don't have its request at hand. Unlike `push_flash`, it doesn't need
+    /// anyone watching. Returns `false` if the session isn't stored.
+    pub fn enqueue_flash(hashed_id: &HashedId, message: impl Serialize) -> Result<bool, SessionsError> {
+        let Ok(message) = serde_json::to_value(message) else {
+            return Ok(false);
+        };
+
+        let mut sessions = SESSIONS.write().unwrap();
+        let Some(mut map) = sessions.get(hashed_id).map(|session| session.map.clone()) else {
+            return Ok(false);
+        };
+
+        let queue = map
+            .entry(FLASH_QUEUE_KEY.into())
+            .or_insert_with(|| serde_json::Value::Array(Vec::new()));
+
+        let queued = match queue {
+            serde_json::Value::Array(queue) => {
+                queue.push(message);
+                queue.len()
+            },
+            _ => {
+                *queue = serde_json::json!([message]);
+                1
+            },
+        };
+
+        let outcome = sessions.write(hashed_id, map)?;
+        drop(sessions);
+
+        Self::notify(hashed_id, &outcome);
+        tracing::info!(event = "flash.enqueued", session = %hashed_id, queued);
+        return Ok(true);
+    }
+
+    /// Destroys a session by its hashed id. Returns `false` if it isn't stored.
+    pub fn revoke(hashed_id: &HashedId) -> bool {
+        let removed = SESSIONS.write().unwrap().remove(hashed_id);
+        if removed.is_some() {
+            Self::emit(hashed_id, SessionEvent::Destroyed);
+        }
+
+        return removed.is_some();
+    }
+
+    /// Destroys every stored session. Returns how many there were.
+    pub fn revoke_all() -> usize {
+        let mut sessions = SESSIONS.write().unwrap();
+        let hashed_ids = sessions.sessions.keys().cloned().collect::<Vec<_>>();
+        for hashed_id in &hashed_ids {
+            sessions.remove(hashed_id);
+        }
+        drop(sessions);
+
+        for hashed_id in &hashed_ids {
+            Self::emit(hashed_id, SessionEvent::Destroyed);
+        }
+
+        return hashed_ids.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    fn map(entries: serde_json::Value) -> SessionMap {
+        return serde_json::from_value(entries).unwrap();
+    }
+
+    #[test]
+    fn forwarded_values_fill_the_keys_a_parallel_request_has_not_set() {
+        let mut forwarded = map(json!({"a": 1, "b": 2}));
+        merge_forwarded(&HashedId::new("id"), &mut forwarded, map(json!({"b": 3, "c": 4})));
+
+        assert_eq!(forwarded, map(json!({"a": 1, "b": 3, "c": 4})));
+    }
+
+    #[test]
+    fn replaced_flash_is_enqueued_after_the_parallel_one() {
+        let mut forwarded = map(json!({"flash": "forwarded", "flash_queue": ["queued"]}));
+        merge_forwarded(&HashedId::new("id"), &mut forwarded, map(json!({"flash": "parallel"})));
+
+        assert_eq!(forwarded, map(json!({"flash": "parallel", "flash_queue": ["forwarded", "queued"]})));
+    }
+
+    #[test]
+    fn both_flash_queues_are_kept() {
+        let mut forwarded = map(json!({"flash": "forwarded"}));
+        let stored = map(json!({"flash": "parallel", "flash_queue": ["enqueued"]}));
+        merge_forwarded(&HashedId::new("id"), &mut forwarded, stored);
+
+        assert_eq!(forwarded, map(json!({"flash": "parallel", "flash_queue": ["forwarded", "enqueued"]})));
+    }
+
+    fn limited_store(limits: SessionsLimits) -> SessionsStore {
+        return SessionsStore { limits, ..SessionsStore::default() };
+    }
+
+    #[test]
+    fn least_recently_used_sessions_are_evicted_first() {
+        let mut store = limited_store(SessionsLimits { max_sessions: Some(2), ..SessionsLimits::default() });
+        let (a, b, c) = (HashedId::new("a"), HashedId::new("b"), HashedId::new("c"));
+        store.write(&a, map(json!({}))).unwrap();
+        store.write(&b, map(json!({}))).unwrap();
+        store.write(&a, map(json!({"x": 1}))).unwrap();
+
+        let outcome = store.write(&c, map(json!({}))).unwrap();
+        assert!(outcome.evicted == vec![b.clone()]);
+        assert!(store.get(&a).is_some() && store.get(&b).is_none() && store.get(&c).is_some());
+    }
+
+    #[test]
+    fn the_session_being_written_is_never_evicted() {
+        let mut store = limited_store(SessionsLimits { max_total_bytes: Some(16), ..SessionsLimits::default() });
+        let (a, b) = (HashedId::new("a"), HashedId::new("b"));
+        store.write(&a, map(json!({"x": 1}))).unwrap();
+
+        let outcome = store.write(&b, map(json!({"value": "longer than the whole store"}))).unwrap();
+        assert!(outcome.evicted == vec![a.clone()]);
+        assert!(store.get(&b).is_some());
+        assert_eq!(store.len(), 1);
+    }
+
+    #[test]
+    fn sessions_over_the_size_limit_are_refused() {
+        let mut store = limited_store(SessionsLimits { max_session_bytes: Some(8), ..SessionsLimits::default() });
+        let written = store.write(&HashedId::new("a"), map(json!({"value": "too large"})));
+
+        assert!(matches!(written, Err(SessionsError::TooLarge { .. })));
+        assert_eq!(store.len(), 0);
+    }
+
+    #[test]
+    fn writes_from_a_stale_version_are_refused() {
+        let session_id = Sessions::store_new_session(map(json!({"a": 1}))).unwrap();
+        let version = Sessions::compare_and_put(&session_id, 0, map(json!({"a": 2}))).unwrap();
+
+        let stale = Sessions::compare_and_put(&session_id, 0, map(json!({"a": 3})));
+        assert!(matches!(stale, Err(SessionsError::VersionConflict { expected: 0, found }) if found == version));
+
+        let sessions = Sessions::all();
+        assert_eq!(sessions.get(&HashedId::new(&session_id)).unwrap().map, map(json!({"a": 2})));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn taking_values_leaves_the_others_in_the_store() {
+        let stored = map(json!({"flash": "hi", "flash_queue": ["queued"], "name": "ana", KEPT_KEY: ["flash", "name"]}));
+        let session_id = Sessions::store_new_session(stored).unwrap();
+
+        let taken = Sessions::take_values(&session_id, &[FLASH_KEY, FLASH_QUEUE_KEY]).unwrap();
+        assert_eq!(taken, map(json!({"flash": "hi", "flash_queue": ["queued"]})));
+
+        let sessions = Sessions::all();
+        let left = &sessions.get(&HashedId::new(&session_id)).unwrap().map;
+        assert_eq!(*left, map(json!({"name": "ana", KEPT_KEY: ["name"]})));
+    }
+
+    #[test]
+    fn watchers_lagging_too_far_behind_are_dropped() {
+        let hashed_id = HashedId::new(&Uuid::new_v4().to_string());
+        let mut receiver = Sessions::watch(&hashed_id);
+        for _ in 0..WATCHER_CAPACITY {
+            assert_eq!(Sessions::push_flash(&hashed_id, "hi"), 1);
+        }
+
+        assert_eq!(Sessions::push_flash(&hashed_id, "hi"), 0);
+        for _ in 0..WATCHER_CAPACITY {
+            assert!(receiver.try_recv().is_ok());
+        }
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn watchers_that_went_away_are_dropped_on_reconnect() {
+        let hashed_id = HashedId::new(&Uuid::new_v4().to_string());
+        for _ in 0..100 {
+            drop(Sessions::watch(&hashed_id));
+        }
+
+        let _receiver = Sessions::watch(&hashed_id);
+        assert_eq!(WATCHERS.lock().unwrap()[&hashed_id].len(), 1);
+    }
+}